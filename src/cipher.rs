@@ -0,0 +1,260 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+/// Returns the path of the SQLCipher settings of the database at `database`, stored next to it in plaintext since they
+/// are needed before the database can be decrypted.
+pub fn settings_path(database: &Path) -> PathBuf {
+    let mut name = OsString::from(database.as_os_str());
+    name.push(".cipher");
+    PathBuf::from(name)
+}
+
+/// HMAC algorithm authenticating the pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    fn pragma_value(self) -> &'static str {
+        match self {
+            HmacAlgorithm::Sha1 => "HMAC_SHA1",
+            HmacAlgorithm::Sha256 => "HMAC_SHA256",
+            HmacAlgorithm::Sha512 => "HMAC_SHA512",
+        }
+    }
+}
+
+impl FromStr for HmacAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha1" => Ok(HmacAlgorithm::Sha1),
+            "sha256" => Ok(HmacAlgorithm::Sha256),
+            "sha512" => Ok(HmacAlgorithm::Sha512),
+            _ => bail!("unknown HMAC algorithm {s}, expected sha1, sha256 or sha512"),
+        }
+    }
+}
+
+impl std::fmt::Display for HmacAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HmacAlgorithm::Sha1 => write!(f, "sha1"),
+            HmacAlgorithm::Sha256 => write!(f, "sha256"),
+            HmacAlgorithm::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+/// SQLCipher settings of an encrypted database, the defaults of the bundled SQLCipher when unset. They must be the
+/// same every time the database is opened, so they are kept in a file next to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CipherSettings {
+    /// Defaults of a major SQLCipher version, to open databases created by it. The other settings override them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compatibility: Option<u8>,
+    /// PBKDF2 iterations deriving the key from the passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf_iter: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac_algorithm: Option<HmacAlgorithm>,
+    /// The key is 64 hex digits used as the encryption key, or 96 with the salt, instead of a passphrase.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub raw_key: bool,
+}
+
+impl CipherSettings {
+    pub fn is_default(&self) -> bool {
+        *self == CipherSettings::default()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(compatibility) = self.compatibility {
+            if !(1..=4).contains(&compatibility) {
+                bail!("SQLCipher compatibility must be between 1 and 4");
+            }
+        }
+        if self.kdf_iter == Some(0) {
+            bail!("KDF iterations must be at least 1");
+        }
+        if let Some(page_size) = self.page_size {
+            if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+                bail!("Cipher page size must be a power of two between 512 and 65536");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the settings of the database at `database`, the defaults when it has none.
+    pub fn load(database: &Path) -> anyhow::Result<CipherSettings> {
+        let path = settings_path(database);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CipherSettings::default()),
+            Err(e) => return Err(e).with_context(|| format!("unable to read {}", path.display())),
+        };
+        let settings: CipherSettings =
+            serde_json::from_slice(&data).with_context(|| format!("unable to parse {}", path.display()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Replaces the settings of the database at `database`, the defaults removing the file.
+    pub fn save(&self, database: &Path) -> anyhow::Result<()> {
+        let path = settings_path(database);
+        if self.is_default() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = fs::File::create(&tmp).with_context(|| format!("unable to write {}", path.display()))?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Returns `key` as given to `PRAGMA key` or `PRAGMA rekey`: raw keys in the `x'...'` notation, which they may
+    /// already use.
+    pub fn sqlcipher_key(&self, key: String) -> anyhow::Result<String> {
+        if !self.raw_key {
+            return Ok(key);
+        }
+        let digits = key
+            .strip_prefix("x'")
+            .and_then(|key| key.strip_suffix('\''))
+            .unwrap_or(&key);
+        if !matches!(digits.len(), 64 | 96) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid raw key: expected 64 hex digits, or 96 with the salt");
+        }
+        Ok(format!("x'{digits}'"))
+    }
+
+    /// Applies the settings to `db`, between `PRAGMA key` and the first read.
+    pub fn apply(&self, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+        // Resets the other settings, so it goes first.
+        if let Some(compatibility) = self.compatibility {
+            db.pragma_update(None, "cipher_compatibility", compatibility)?;
+        }
+        if let Some(kdf_iter) = self.kdf_iter {
+            db.pragma_update(None, "kdf_iter", kdf_iter)?;
+        }
+        if let Some(page_size) = self.page_size {
+            db.pragma_update(None, "cipher_page_size", page_size)?;
+        }
+        if let Some(hmac_algorithm) = self.hmac_algorithm {
+            db.pragma_update(None, "cipher_hmac_algorithm", hmac_algorithm.pragma_value())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for CipherSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut settings = Vec::new();
+        if let Some(compatibility) = self.compatibility {
+            settings.push(format!("compatibility={compatibility}"));
+        }
+        if let Some(kdf_iter) = self.kdf_iter {
+            settings.push(format!("kdf_iter={kdf_iter}"));
+        }
+        if let Some(page_size) = self.page_size {
+            settings.push(format!("page_size={page_size}"));
+        }
+        if let Some(hmac_algorithm) = self.hmac_algorithm {
+            settings.push(format!("hmac={hmac_algorithm}"));
+        }
+        if self.raw_key {
+            settings.push("raw_key".to_owned());
+        }
+        if settings.is_empty() {
+            write!(f, "default")
+        } else {
+            write!(f, "{}", settings.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::{settings_path, CipherSettings, HmacAlgorithm};
+
+    #[test]
+    fn test_cipher_settings() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let database = tmp.path().join("db.sqlite");
+        assert!(CipherSettings::load(&database)?.is_default());
+
+        let settings = CipherSettings {
+            compatibility: Some(3),
+            kdf_iter: Some(1000),
+            page_size: Some(8192),
+            hmac_algorithm: Some("SHA256".parse()?),
+            raw_key: false,
+        };
+        settings.save(&database)?;
+        assert_eq!(CipherSettings::load(&database)?, settings);
+        assert_eq!(
+            settings.to_string(),
+            "compatibility=3 kdf_iter=1000 page_size=8192 hmac=sha256"
+        );
+        CipherSettings::default().save(&database)?;
+        assert!(!settings_path(&database).exists());
+
+        for invalid in [
+            CipherSettings {
+                compatibility: Some(5),
+                ..Default::default()
+            },
+            CipherSettings {
+                kdf_iter: Some(0),
+                ..Default::default()
+            },
+            CipherSettings {
+                page_size: Some(1000),
+                ..Default::default()
+            },
+            CipherSettings {
+                page_size: Some(256),
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{invalid}");
+        }
+        assert!("md5".parse::<HmacAlgorithm>().is_err());
+
+        let raw = CipherSettings {
+            raw_key: true,
+            ..Default::default()
+        };
+        let digits = "0123456789abcdef".repeat(4);
+        assert_eq!(raw.sqlcipher_key(digits.clone())?, format!("x'{digits}'"));
+        assert_eq!(raw.sqlcipher_key(format!("x'{digits}'"))?, format!("x'{digits}'"));
+        assert!(raw.sqlcipher_key("passphrase".to_owned()).is_err());
+        assert!(raw.sqlcipher_key(format!("{digits}0")).is_err());
+        assert_eq!(
+            CipherSettings::default().sqlcipher_key("passphrase".to_owned())?,
+            "passphrase"
+        );
+        Ok(())
+    }
+}
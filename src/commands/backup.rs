@@ -0,0 +1,167 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use rusqlite::backup::{Backup, Progress, StepResult};
+use rusqlite::{params, DatabaseName};
+
+use crate::cipher::CipherSettings;
+use crate::database::DatabaseOps;
+
+/// Encryption of a backup, relative to the database it is copied from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupKey {
+    /// Encrypted with the key of the source, or plaintext when the source is.
+    Same,
+    Key(String),
+    Plaintext,
+}
+
+/// How the pages are copied by the SQLite backup API.
+#[derive(Debug, Clone, Copy)]
+pub struct BackupSteps {
+    /// Pages copied per step, the source is only read locked during a step. `None` copies everything at once.
+    pub pages: Option<u32>,
+    /// Pause between two steps, leaving the source to its writers.
+    pub pause: Duration,
+}
+
+/// Copies the database `db`, opened with `key`, to the new file `out` with the online backup API, so that the copy is
+/// consistent even while the database is mounted by another process. Writes made to the source during an incremental
+/// backup make it start over. SQLCipher only copies pages between two encrypted or two plaintext databases: encrypting
+/// or decrypting goes through `sqlcipher_export` instead, in a single read transaction without progress. A copy with a
+/// new key uses the default cipher settings. The backup is opened again once written to verify it.
+pub fn backup(
+    db: &mut DatabaseOps,
+    key: Option<String>,
+    out: &Path,
+    backup_key: BackupKey,
+    steps: BackupSteps,
+    mut progress: impl FnMut(Progress),
+) -> anyhow::Result<()> {
+    if out.exists() {
+        bail!("{} already exists", out.display());
+    }
+    let out_key = match &backup_key {
+        BackupKey::Same => key.clone(),
+        BackupKey::Key(key) => Some(key.clone()),
+        BackupKey::Plaintext => None,
+    };
+
+    // A copy with the same key gets the same cipher settings, the others the defaults.
+    let cipher = match backup_key {
+        BackupKey::Same => db.cipher,
+        _ => CipherSettings::default(),
+    };
+
+    if key.is_some() == out_key.is_some() {
+        let mut dst = rusqlite::Connection::open(out).context("open backup")?;
+        if let Some(out_key) = &out_key {
+            dst.pragma_update(None, "key", cipher.sqlcipher_key(out_key.clone())?)?;
+            cipher.apply(&dst)?;
+        }
+        let backup = Backup::new(&db.db, &mut dst)?;
+        let pages = steps.pages.map_or(-1, |pages| pages.clamp(1, i32::MAX as u32) as i32);
+        loop {
+            match backup.step(pages)? {
+                StepResult::Done => break,
+                StepResult::Busy | StepResult::Locked => log::debug!("Backup source busy, retrying"),
+                _ => {}
+            }
+            progress(backup.progress());
+            std::thread::sleep(steps.pause);
+        }
+        progress(backup.progress());
+    } else {
+        let out_path = out.to_str().context("backup path must be UTF-8")?;
+        // Attached databases are opened with the flags of the source, which cannot create files.
+        std::fs::File::create_new(out).context("create backup")?;
+        db.db.execute(
+            "ATTACH DATABASE ? AS backup KEY ?",
+            params![out_path, out_key.as_deref().unwrap_or_default()],
+        )?;
+        // The schema version tells the migrations already applied, sqlcipher_export does not always copy it.
+        let exported = db
+            .db
+            .query_row("SELECT sqlcipher_export('backup')", params![], |_| Ok(()))
+            .and_then(|_| {
+                let version: u32 = db.db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+                db.db
+                    .pragma_update(Some(DatabaseName::Attached("backup")), "user_version", version)
+            });
+        db.db.execute("DETACH DATABASE backup", params![])?;
+        exported.context("sqlcipher_export")?;
+    }
+
+    cipher.save(out)?;
+    DatabaseOps::open(out, out_key).context("unable to open the backup")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use test_log::test;
+
+    use super::{backup, BackupKey, BackupSteps};
+    use crate::cipher::CipherSettings;
+    use crate::commands::get::cat;
+    use crate::database::{CreateOptions, DatabaseOps};
+    use crate::test_util::populated_db_with;
+
+    #[test]
+    fn test_backup() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let key = Some("secret".to_owned());
+        let options = CreateOptions {
+            cipher: CipherSettings {
+                kdf_iter: Some(1000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut db, data) = populated_db_with(&tmp.path().join("db.sqlite"), key.clone(), &options)?;
+        let all = BackupSteps {
+            pages: None,
+            pause: Duration::ZERO,
+        };
+
+        let same = tmp.path().join("same.sqlite");
+        let mut calls = Vec::new();
+        let steps = BackupSteps { pages: Some(10), ..all };
+        backup(&mut db, key.clone(), &same, BackupKey::Same, steps, |p| {
+            calls.push(p.remaining)
+        })?;
+        assert!(calls.len() > 2);
+        assert_eq!(calls.last(), Some(&0));
+        assert!(DatabaseOps::open(&same, None).is_err());
+        assert_eq!(DatabaseOps::open(&same, key.clone())?.cipher, options.cipher);
+        assert!(backup(&mut db, key.clone(), &same, BackupKey::Same, all, |_| {}).is_err());
+
+        let rekeyed = tmp.path().join("rekeyed.sqlite");
+        let new_key = BackupKey::Key("other".to_owned());
+        backup(&mut db, key.clone(), &rekeyed, new_key, all, |_| {})?;
+        assert!(DatabaseOps::open(&rekeyed, key.clone()).is_err());
+        assert!(DatabaseOps::open(&rekeyed, Some("other".to_owned()))?
+            .cipher
+            .is_default());
+
+        let plain = tmp.path().join("plain.sqlite");
+        backup(&mut db, key.clone(), &plain, BackupKey::Plaintext, all, |_| {})?;
+        let mut plain_db = DatabaseOps::open(&plain, None)?;
+        let mut out = Vec::new();
+        cat(&mut plain_db, Path::new("/src/random"), &mut out)?;
+        assert_eq!(out, data);
+
+        let encrypted = tmp.path().join("encrypted.sqlite");
+        let new_key = BackupKey::Key("again".to_owned());
+        backup(&mut plain_db, None, &encrypted, new_key, all, |_| {})?;
+        let mut encrypted_db = DatabaseOps::open(&encrypted, Some("again".to_owned()))?;
+        out.clear();
+        cat(&mut encrypted_db, Path::new("/src/random"), &mut out)?;
+        assert_eq!(out, data);
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStringExt;
+
+use crate::database::DatabaseOps;
+use crate::driver::attr::FileAttrBuilder;
+use crate::errors::{Error, Result};
+use crate::queries::{self, block::Compression, fsck::Entry};
+use crate::types::FileType;
+
+/// Directory of the root directory where repaired inodes that were not reachable are linked, as `#<ino>`.
+const LOST_AND_FOUND: &str = "lost+found";
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    MissingRoot,
+    InvalidKind {
+        ino: u64,
+        kind: i64,
+    },
+    InvalidCompression {
+        ino: u64,
+        bno: Option<u64>,
+        compression: i64,
+    },
+    OrphanBlocks {
+        ino: u64,
+    },
+    DanglingEntry {
+        parent: u64,
+        name: String,
+        ino: u64,
+    },
+    DuplicateName {
+        parent: u64,
+        name: String,
+        ino: u64,
+    },
+    Orphan {
+        ino: u64,
+    },
+    Unreachable {
+        ino: u64,
+    },
+    WrongNlink {
+        ino: u64,
+        nlink: u64,
+        expected: u64,
+    },
+    WrongSize {
+        ino: u64,
+        size: u64,
+        expected: u64,
+    },
+    WrongBlocks {
+        ino: u64,
+        blocks: u64,
+        expected: u64,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::MissingRoot => write!(f, "root directory is missing"),
+            Problem::InvalidKind { ino, kind } => write!(f, "inode {ino} has invalid kind {kind}"),
+            Problem::InvalidCompression {
+                ino,
+                bno: Some(bno),
+                compression,
+            } => write!(f, "block {bno} of inode {ino} has invalid compression {compression}"),
+            Problem::InvalidCompression {
+                ino,
+                bno: None,
+                compression,
+            } => write!(f, "inline data of inode {ino} has invalid compression {compression}"),
+            Problem::OrphanBlocks { ino } => write!(f, "blocks belong to missing inode {ino}"),
+            Problem::DanglingEntry { parent, name, ino } => {
+                write!(f, "entry {name:?} of inode {parent} to inode {ino} is dangling")
+            }
+            Problem::DuplicateName { parent, name, ino } => {
+                write!(f, "entry {name:?} of directory {parent} to inode {ino} is a duplicate")
+            }
+            Problem::Orphan { ino } => write!(f, "inode {ino} has no directory entry"),
+            Problem::Unreachable { ino } => write!(f, "inode {ino} is not reachable from the root directory"),
+            Problem::WrongNlink { ino, nlink, expected } => {
+                write!(f, "inode {ino} has nlink {nlink}, expected {expected}")
+            }
+            Problem::WrongSize { ino, size, expected } => {
+                write!(f, "inode {ino} has size {size}, expected {expected}")
+            }
+            Problem::WrongBlocks { ino, blocks, expected } => {
+                write!(f, "inode {ino} has {blocks} blocks, expected {expected}")
+            }
+        }
+    }
+}
+
+/// Checks the structure of the filesystem and returns the problems found. With `repair`, each problem is fixed in a
+/// single transaction before the next class of problems is checked, so that the repairs build on each other.
+pub fn fsck(db: &mut DatabaseOps, repair: bool) -> anyhow::Result<Vec<Problem>> {
+    let block_size = db.block_size()?;
+    let problems = match repair {
+        false => db.with_read_tx(|tx| check(tx, block_size, false))?,
+        true => db.with_write_tx(|tx| check(tx, block_size, true))?,
+    };
+    Ok(problems)
+}
+
+fn check(tx: &mut rusqlite::Transaction, block_size: u64, repair: bool) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    check_root(tx, repair, &mut problems)?;
+    check_kinds(tx, repair, &mut problems)?;
+    check_compressions(tx, repair, &mut problems)?;
+    check_entries(tx, repair, &mut problems)?;
+    check_reachability(tx, repair, &mut problems)?;
+    check_nlinks(tx, repair, &mut problems)?;
+    check_sizes(tx, block_size, repair, &mut problems)?;
+    Ok(problems)
+}
+
+fn check_root(tx: &mut rusqlite::Transaction, repair: bool, problems: &mut Vec<Problem>) -> Result<()> {
+    if queries::fsck::exists(tx, 1)? {
+        return Ok(());
+    }
+    problems.push(Problem::MissingRoot);
+    if repair {
+        let mut attr = FileAttrBuilder::new_directory().build();
+        queries::inode::create(tx, &mut attr)?;
+        queries::fsck::renumber(tx, attr.ino, 1)?;
+    }
+    Ok(())
+}
+
+fn check_kinds(tx: &mut rusqlite::Transaction, repair: bool, problems: &mut Vec<Problem>) -> Result<()> {
+    for (ino, kind) in queries::fsck::invalid_kinds(tx)? {
+        problems.push(Problem::InvalidKind { ino, kind });
+        if repair {
+            let kind = match queries::dir_entry::is_dir_empty(tx, ino)? {
+                true if ino != 1 => FileType::RegularFile,
+                _ => FileType::Directory,
+            };
+            queries::inode::set_attr(tx, ino, "kind", u8::from(kind))?;
+        }
+    }
+    Ok(())
+}
+
+fn check_compressions(tx: &mut rusqlite::Transaction, repair: bool, problems: &mut Vec<Problem>) -> Result<()> {
+    let is_invalid = |compression: &Option<i64>| match compression {
+        None => false,
+        Some(c) => u8::try_from(*c).map_or(true, |c| Compression::try_from(Some(c)).is_err()),
+    };
+    for compression in queries::fsck::compressions(tx, false)?
+        .into_iter()
+        .filter(is_invalid)
+        .flatten()
+    {
+        for (ino, bno) in queries::fsck::blocks_with_compression(tx, compression)? {
+            problems.push(Problem::InvalidCompression {
+                ino,
+                bno: Some(bno),
+                compression,
+            });
+            if repair {
+                queries::fsck::remove_block(tx, ino, bno)?;
+            }
+        }
+    }
+    for compression in queries::fsck::compressions(tx, true)?
+        .into_iter()
+        .filter(is_invalid)
+        .flatten()
+    {
+        for ino in queries::fsck::inline_with_compression(tx, compression)? {
+            problems.push(Problem::InvalidCompression {
+                ino,
+                bno: None,
+                compression,
+            });
+            if repair {
+                queries::inode::clear_inline(tx, ino)?;
+            }
+        }
+    }
+    for ino in queries::fsck::orphan_blocks(tx)? {
+        problems.push(Problem::OrphanBlocks { ino });
+        if repair {
+            queries::fsck::remove_blocks(tx, ino)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_entries(tx: &mut rusqlite::Transaction, repair: bool, problems: &mut Vec<Problem>) -> Result<()> {
+    for Entry {
+        rowid,
+        parent_ino,
+        name,
+        ino,
+    } in queries::fsck::dangling_entries(tx)?
+    {
+        problems.push(Problem::DanglingEntry {
+            parent: parent_ino,
+            name: name.to_string_lossy().into_owned(),
+            ino,
+        });
+        if repair {
+            queries::fsck::remove_entry(tx, rowid)?;
+        }
+    }
+    // Duplicates are renamed rather than removed, they may be the only entry of their inode.
+    for Entry {
+        rowid,
+        parent_ino,
+        name,
+        ino,
+    } in queries::fsck::duplicate_entries(tx)?
+    {
+        problems.push(Problem::DuplicateName {
+            parent: parent_ino,
+            name: name.to_string_lossy().into_owned(),
+            ino,
+        });
+        if repair {
+            let mut renamed = name.into_vec();
+            renamed.extend_from_slice(format!("#{ino}").as_bytes());
+            queries::fsck::rename_entry(tx, rowid, parent_ino, &renamed)?;
+        }
+    }
+    Ok(())
+}
+
+fn lost_and_found(tx: &mut rusqlite::Transaction) -> Result<u64> {
+    match queries::dir_entry::lookup(tx, 1, OsStr::new(LOST_AND_FOUND)) {
+        Err(Error::NotFound) => {
+            let mut attr = FileAttrBuilder::new_directory().with_mode_umask(0o700, 0).build();
+            queries::inode::create(tx, &mut attr)?;
+            queries::dir_entry::create(tx, 1, OsStr::new(LOST_AND_FOUND), attr.ino)?;
+            Ok(attr.ino)
+        }
+        res => res,
+    }
+}
+
+fn check_reachability(tx: &mut rusqlite::Transaction, repair: bool, problems: &mut Vec<Problem>) -> Result<()> {
+    let mut unreachable = queries::fsck::unreachable(tx)?;
+    for inode in &unreachable {
+        problems.push(match inode.linked {
+            false => Problem::Orphan { ino: inode.ino },
+            true => Problem::Unreachable { ino: inode.ino },
+        });
+    }
+    if !repair || unreachable.is_empty() {
+        return Ok(());
+    }
+
+    let lost_and_found = lost_and_found(tx)?;
+    // Relink the roots of the unreachable trees first: directories without entries, then other inodes without
+    // entries. What remains are cycles of directories, broken by moving one of their entries.
+    while !unreachable.is_empty() {
+        let directory = i64::from(u8::from(FileType::Directory));
+        let inode = unreachable
+            .iter()
+            .find(|inode| !inode.linked && inode.kind == directory)
+            .or_else(|| unreachable.iter().find(|inode| !inode.linked))
+            .unwrap_or(&unreachable[0]);
+        let name = format!("#{}", inode.ino);
+        log::info!("Linking inode {} to /{}/{}", inode.ino, LOST_AND_FOUND, name);
+        match queries::fsck::entries_of(tx, inode.ino)?.first() {
+            Some(entry) => queries::fsck::rename_entry(tx, entry.rowid, lost_and_found, name.as_bytes())?,
+            None => queries::dir_entry::create(tx, lost_and_found, OsStr::new(&name), inode.ino)?,
+        }
+        unreachable = queries::fsck::unreachable(tx)?;
+    }
+    Ok(())
+}
+
+fn check_nlinks(tx: &mut rusqlite::Transaction, repair: bool, problems: &mut Vec<Problem>) -> Result<()> {
+    let directory = i64::from(u8::from(FileType::Directory));
+    for (ino, kind, nlink, entries) in queries::fsck::link_counts(tx)? {
+        // Directories keep the nlink of 2 they are created with. Inodes without entries are reported as orphans.
+        let expected = match kind == directory {
+            true => 2,
+            false if entries == 0 => continue,
+            false => entries,
+        };
+        if nlink != expected {
+            problems.push(Problem::WrongNlink { ino, nlink, expected });
+            if repair {
+                queries::inode::set_attr(tx, ino, "nlink", expected)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_sizes(
+    tx: &mut rusqlite::Transaction,
+    block_size: u64,
+    repair: bool,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    for file in queries::fsck::file_sizes(tx)? {
+        let extent = match (file.inline, file.last_bno) {
+            (true, _) => {
+                queries::inode::get_inline(tx, file.ino, block_size).map(|b| b.map_or(0, |b| b.data.len() as u64))
+            }
+            (false, Some(bno)) => {
+                queries::block::get_block(tx, file.ino, bno, block_size).map(|b| bno * block_size + b.data.len() as u64)
+            }
+            (false, None) => Ok(0),
+        };
+        // Files can be larger than their data when they end with a hole, never smaller. Corrupted data is left to
+        // scrub, the size of the file is then trusted.
+        let size = match extent {
+            Ok(extent) if extent > file.size => {
+                problems.push(Problem::WrongSize {
+                    ino: file.ino,
+                    size: file.size,
+                    expected: extent,
+                });
+                if repair {
+                    queries::inode::set_attr(tx, file.ino, "size", extent)?;
+                }
+                extent
+            }
+            Ok(_) | Err(Error::Corrupted) => file.size,
+            Err(e) => return Err(e),
+        };
+        if file.blksize == 0 {
+            continue;
+        }
+        let expected = size.div_ceil(file.blksize);
+        if file.blocks != expected {
+            problems.push(Problem::WrongBlocks {
+                ino: file.ino,
+                blocks: file.blocks,
+                expected,
+            });
+            if repair {
+                queries::inode::set_attr(tx, file.ino, "blocks", expected)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    use test_log::test;
+
+    use super::{fsck, Problem};
+    use crate::database::DatabaseOps;
+    use crate::queries;
+    use crate::queries::block::DEFAULT_BLOCK_SIZE;
+    use crate::test_util::{create, sample_data};
+    use crate::types::FileType;
+
+    #[test]
+    fn test_fsck() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = sample_data();
+
+        let root = create(&mut db, None, "", FileType::Directory, b"")?;
+        assert_eq!(root, 1);
+        let a = create(&mut db, Some(root), "a", FileType::Directory, b"")?;
+        let f = create(&mut db, Some(a), "f", FileType::RegularFile, &data)?;
+        let g = create(&mut db, Some(root), "g", FileType::RegularFile, b"hello")?;
+        assert!(fsck(&mut db, false)?.is_empty());
+
+        let o = create(&mut db, None, "", FileType::Directory, b"")?;
+        let x = create(&mut db, Some(o), "x", FileType::RegularFile, b"x")?;
+        let h = create(&mut db, Some(root), "g", FileType::RegularFile, b"")?;
+        let k = create(&mut db, Some(root), "k", FileType::RegularFile, b"")?;
+        db.db.execute_batch("PRAGMA foreign_keys = OFF")?;
+        db.db.execute_batch(&format!(
+            "INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, CAST('ghost' AS BLOB), 999);
+            INSERT INTO block (ino, bno, data) VALUES (12345, 0, x'00');
+            INSERT INTO block (ino, bno, data, compression) VALUES ({g}, 3, x'00', 200);
+            UPDATE inode SET nlink = 5, blocks = 0 WHERE ino = {g};
+            UPDATE inode SET size = 10 WHERE ino = {f};
+            UPDATE inode SET kind = 42 WHERE ino = {k};"
+        ))?;
+        db.db.execute_batch("PRAGMA foreign_keys = ON")?;
+
+        let expected = vec![
+            Problem::InvalidKind { ino: k, kind: 42 },
+            Problem::InvalidCompression {
+                ino: g,
+                bno: Some(3),
+                compression: 200,
+            },
+            Problem::OrphanBlocks { ino: 12345 },
+            Problem::DanglingEntry {
+                parent: 1,
+                name: "ghost".to_owned(),
+                ino: 999,
+            },
+            Problem::DuplicateName {
+                parent: 1,
+                name: "g".to_owned(),
+                ino: h,
+            },
+            Problem::Orphan { ino: o },
+            Problem::Unreachable { ino: x },
+            Problem::WrongNlink {
+                ino: g,
+                nlink: 5,
+                expected: 1,
+            },
+            Problem::WrongSize {
+                ino: f,
+                size: 10,
+                expected: data.len() as u64,
+            },
+            Problem::WrongBlocks {
+                ino: g,
+                blocks: 0,
+                expected: 1,
+            },
+        ];
+        assert_eq!(fsck(&mut db, false)?, expected);
+        // Checking alone changes nothing.
+        assert_eq!(fsck(&mut db, false)?, expected);
+        assert_eq!(fsck(&mut db, true)?, expected);
+        assert_eq!(fsck(&mut db, false)?, vec![]);
+
+        db.with_read_tx(|tx| {
+            assert_eq!(
+                queries::dir_entry::resolve(tx, Path::new(&format!("/lost+found/#{o}/x")))?,
+                x
+            );
+            assert_eq!(queries::dir_entry::resolve(tx, Path::new(&format!("/g#{h}")))?, h);
+            assert!(queries::dir_entry::lookup(tx, 1, OsStr::new("ghost")).is_err());
+
+            let attr = queries::inode::lookup(tx, k)?;
+            assert_eq!(attr.kind, fuser::FileType::RegularFile);
+            let attr = queries::inode::lookup(tx, f)?;
+            assert_eq!((attr.size, attr.blocks), (data.len() as u64, data.len() as u64 / 512));
+            let attr = queries::inode::lookup(tx, g)?;
+            assert_eq!((attr.nlink, attr.blocks), (1, 1));
+            assert!(queries::block::get_block(tx, g, 3, DEFAULT_BLOCK_SIZE).is_err());
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
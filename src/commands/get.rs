@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::bail;
+
+use crate::database::DatabaseOps;
+use crate::errors::{Error, Result};
+use crate::queries;
+use crate::time::TimeSpec;
+
+#[derive(Debug, Default)]
+pub struct GetStats {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+}
+
+fn io_error(path: &Path, err: io::Error) -> Error {
+    Error::Other(format!("{}: {}", path.display(), err))
+}
+
+fn write_zeros(out: &mut impl Write, len: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(len), out)?;
+    Ok(())
+}
+
+/// Writes the first `size` bytes of the data of `ino` to `out`. Holes, missing blocks and the end of a file beyond its
+/// last block, are written as zeros.
+pub fn read_file(
+    tx: &mut rusqlite::Transaction,
+    ino: u64,
+    size: u64,
+    block_size: u64,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut written = 0;
+    let mut res = Ok(());
+    if let Some(block) = queries::inode::get_inline(tx, ino, block_size)? {
+        let len = size.min(block.data.len() as u64);
+        res = out.write_all(&block.data[..len as usize]);
+        written = len;
+    } else {
+        queries::block::iter_blocks_from(tx, ino, 0, block_size, |block| {
+            let start = block.start_offset().min(size);
+            let len = (size - start).min(block.data.len() as u64);
+            res = write_zeros(out, start - written).and_then(|_| out.write_all(&block.data[..len as usize]));
+            written = start + len;
+            Ok(res.is_ok() && written < size)
+        })?;
+    }
+    res.and_then(|_| write_zeros(out, size - written))
+        .map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Writes the content of the regular file at `path` to `out`.
+pub fn cat(db: &mut DatabaseOps, path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    let block_size = db.block_size()?;
+    db.with_read_tx(|tx| {
+        let ino = queries::dir_entry::resolve(tx, path)?;
+        let attr = queries::inode::lookup(tx, ino)?;
+        if attr.kind != fuser::FileType::RegularFile {
+            return Err(Error::InvalidArgument);
+        }
+        read_file(tx, ino, attr.size, block_size, out)
+    })?;
+    Ok(())
+}
+
+/// Copies the file or directory at `path` to `out` on the host, recursively. Permissions and timestamps are restored,
+/// ownership only when the process is allowed to change it. Hard links within the copied tree are kept.
+pub fn get(db: &mut DatabaseOps, path: &Path, out: &Path) -> anyhow::Result<GetStats> {
+    let block_size = db.block_size()?;
+    let mut extractor = Extractor {
+        block_size,
+        links: HashMap::new(),
+        stats: GetStats::default(),
+    };
+    db.with_read_tx(|tx| {
+        let ino = queries::dir_entry::resolve(tx, path)?;
+        extractor.extract(tx, ino, out)
+    })?;
+    if extractor.stats.files + extractor.stats.directories == 0 {
+        bail!("nothing to extract from {}", path.display());
+    }
+    Ok(extractor.stats)
+}
+
+struct Extractor {
+    block_size: u64,
+    /// Host path of the files extracted so far that have more than one link, by inode.
+    links: HashMap<u64, std::path::PathBuf>,
+    stats: GetStats,
+}
+
+impl Extractor {
+    fn extract(&mut self, tx: &mut rusqlite::Transaction, ino: u64, dest: &Path) -> Result<()> {
+        let attr = queries::inode::lookup(tx, ino)?;
+        if let Some(first) = self.links.get(&ino) {
+            fs::hard_link(first, dest).map_err(|e| io_error(dest, e))?;
+            self.stats.files += 1;
+            return Ok(());
+        }
+
+        match attr.kind {
+            fuser::FileType::Directory => {
+                match fs::create_dir(dest) {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists && dest.is_dir() => {}
+                    res => res.map_err(|e| io_error(dest, e))?,
+                }
+                let mut entries: Vec<(OsString, u64)> = Vec::new();
+                queries::dir_entry::list_dir(tx, ino, 0, |entry| {
+                    entries.push((entry.name.to_owned(), entry.ino));
+                    true
+                })?;
+                for (name, ino) in entries {
+                    self.extract(tx, ino, &dest.join(name))?;
+                }
+                self.stats.directories += 1;
+            }
+            fuser::FileType::RegularFile => {
+                let file = File::create(dest).map_err(|e| io_error(dest, e))?;
+                let mut out = BufWriter::new(file);
+                read_file(tx, ino, attr.size, self.block_size, &mut out)?;
+                out.flush().map_err(|e| io_error(dest, e))?;
+                self.stats.files += 1;
+                self.stats.bytes += attr.size;
+            }
+            fuser::FileType::Symlink => {
+                let mut target = Vec::new();
+                read_file(tx, ino, attr.size, self.block_size, &mut target)?;
+                std::os::unix::fs::symlink(OsString::from_vec(target), dest).map_err(|e| io_error(dest, e))?;
+                self.stats.files += 1;
+            }
+            fuser::FileType::NamedPipe | fuser::FileType::CharDevice | fuser::FileType::BlockDevice => {
+                let mode = attr.perm as libc::mode_t
+                    | match attr.kind {
+                        fuser::FileType::NamedPipe => libc::S_IFIFO,
+                        fuser::FileType::CharDevice => libc::S_IFCHR,
+                        _ => libc::S_IFBLK,
+                    };
+                let c_path = CString::new(dest.as_os_str().as_bytes()).map_err(|_| Error::InvalidArgument)?;
+                // SAFETY: c_path is a valid NUL terminated string.
+                if unsafe { libc::mknod(c_path.as_ptr(), mode, attr.rdev as libc::dev_t) } != 0 {
+                    let err = io::Error::last_os_error();
+                    log::warn!("Skipping {}: {}", dest.display(), err);
+                    return Ok(());
+                }
+                self.stats.files += 1;
+            }
+            fuser::FileType::Socket => {
+                log::warn!("Skipping socket {}", dest.display());
+                return Ok(());
+            }
+        }
+
+        if attr.nlink > 1 && attr.kind != fuser::FileType::Directory {
+            self.links.insert(ino, dest.to_owned());
+        }
+        restore_attributes(dest, &attr)
+    }
+}
+
+/// Applies the owner, permissions and timestamps of `attr` to `path`, without following symlinks.
+fn restore_attributes(path: &Path, attr: &fuser::FileAttr) -> Result<()> {
+    // Ownership first, changing it clears the setuid and setgid bits.
+    if let Err(e) = std::os::unix::fs::lchown(path, Some(attr.uid), Some(attr.gid)) {
+        log::debug!("Unable to change owner of {}: {}", path.display(), e);
+    }
+    if attr.kind != fuser::FileType::Symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(u32::from(attr.perm))).map_err(|e| io_error(path, e))?;
+    }
+
+    let timespec = |time: SystemTime| {
+        let time = TimeSpec::from(time);
+        libc::timespec {
+            tv_sec: time.secs as libc::time_t,
+            tv_nsec: i64::from(time.nanos) as _,
+        }
+    };
+    let times = [timespec(attr.atime), timespec(attr.mtime)];
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::InvalidArgument)?;
+    // SAFETY: c_path is a valid NUL terminated string and times holds the two entries utimensat reads.
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } != 0
+    {
+        return Err(io_error(path, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    use test_log::test;
+
+    use super::{cat, get};
+    use crate::database::DatabaseOps;
+    use crate::queries;
+    use crate::test_util::{create, sample_data};
+    use crate::types::FileType;
+
+    #[test]
+    fn test_get() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = sample_data();
+
+        let root = create(&mut db, None, "", FileType::Directory, b"")?;
+        let dir = create(&mut db, Some(root), "dir", FileType::Directory, b"")?;
+        let big = create(&mut db, Some(dir), "big", FileType::RegularFile, &data)?;
+        let small = create(&mut db, Some(root), "small", FileType::RegularFile, b"hello")?;
+        create(&mut db, Some(root), "link", FileType::Symlink, b"dir/big")?;
+        db.with_write_tx(|tx| {
+            // A hard link, and a hole at the end of the big file.
+            queries::dir_entry::create(tx, dir, OsStr::new("small"), small)?;
+            queries::inode::set_attr(tx, small, "nlink", 2)?;
+            queries::inode::set_attr(tx, small, "perm", 0o600)?;
+            queries::inode::set_attr(tx, small, "mtime_secs", 1_000_000_000)?;
+            queries::inode::set_attr(tx, big, "size", data.len() as u64 + 10)
+        })?;
+
+        let mut out = Vec::new();
+        cat(&mut db, Path::new("/small"), &mut out)?;
+        assert_eq!(out, b"hello");
+        out.clear();
+        cat(&mut db, Path::new("/dir/big"), &mut out)?;
+        assert_eq!(out[..data.len()], data);
+        assert_eq!(out[data.len()..], [0; 10]);
+        assert!(cat(&mut db, Path::new("/dir"), &mut out).is_err());
+
+        let tmp = tempfile::tempdir()?;
+        let dest = tmp.path().join("out");
+        let stats = get(&mut db, Path::new("/"), &dest)?;
+        assert_eq!((stats.files, stats.directories), (4, 2));
+        assert_eq!(fs::read(dest.join("dir/big"))?.len(), data.len() + 10);
+        assert_eq!(fs::read(dest.join("small"))?, b"hello");
+        assert_eq!(fs::read_link(dest.join("link"))?, Path::new("dir/big"));
+
+        let meta = fs::metadata(dest.join("small"))?;
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(meta.mtime(), 1_000_000_000);
+        assert_eq!(meta.nlink(), 2);
+        assert_eq!(meta.ino(), fs::metadata(dest.join("dir/small"))?.ino());
+
+        let stats = get(&mut db, Path::new("/dir/big"), &tmp.path().join("big"))?;
+        assert_eq!((stats.files, stats.bytes), (1, data.len() as u64 + 10));
+        Ok(())
+    }
+}
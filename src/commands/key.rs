@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::commands::rekey::rekey;
+use crate::database::DatabaseOps;
+use crate::keyslots::{header_path, Kdf, KeySlot, KeySlots, MasterKey};
+
+/// Adds a key slot wrapping the master key of the database at `path` with `new_passphrase`, and returns its id.
+/// `passphrase` must unlock one of the existing slots. A database without key slots is first re-encrypted with a new
+/// random master key, its current key `passphrase`, if any, getting the first slot.
+pub fn add(
+    path: &Path,
+    passphrase: Option<String>,
+    new_passphrase: &str,
+    label: Option<String>,
+    kdf: Kdf,
+) -> anyhow::Result<u32> {
+    match KeySlots::load(path)? {
+        Some(mut slots) => {
+            let passphrase = passphrase.context("The key of an existing slot is required")?;
+            let master = slots.unlock(&passphrase)?;
+            // The slots could belong to another database, check before wrapping the key once more.
+            DatabaseOps::open(path, Some(master.sqlcipher_key())).context("open db")?;
+            let id = slots.add(&master, new_passphrase, label, kdf)?;
+            slots.save(path)?;
+            Ok(id)
+        }
+        None => {
+            let master = MasterKey::generate()?;
+            let mut slots = KeySlots::new();
+            if let Some(passphrase) = &passphrase {
+                slots.add(&master, passphrase, None, kdf)?;
+            }
+            let id = slots.add(&master, new_passphrase, label, kdf)?;
+            // Saved first, a database encrypted with a master key that was not saved could never be opened again.
+            slots.save(path)?;
+            if let Err(e) = rekey(path, passphrase, Some(master.sqlcipher_key())) {
+                fs::remove_file(header_path(path))?;
+                return Err(e);
+            }
+            Ok(id)
+        }
+    }
+}
+
+/// Removes the key slot `id` of the database at `path`, `passphrase` must unlock one of its slots. The last slot cannot
+/// be removed.
+pub fn remove(path: &Path, passphrase: &str, id: u32) -> anyhow::Result<()> {
+    let mut slots = KeySlots::load(path)?.context("Database has no key slots")?;
+    slots.unlock(passphrase)?;
+    slots.remove(id)?;
+    slots.save(path)
+}
+
+pub fn list(path: &Path) -> anyhow::Result<Vec<KeySlot>> {
+    Ok(KeySlots::load(path)?.context("Database has no key slots")?.slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use test_log::test;
+
+    use super::{add, list, remove};
+    use crate::commands::get::cat;
+    use crate::database::{CreateOptions, DatabaseOps};
+    use crate::keyslots::{header_path, Kdf, KeySlots};
+    use crate::test_util::populated_db;
+
+    const KDF: Kdf = Kdf::Argon2id {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn open(path: &Path, passphrase: &str) -> anyhow::Result<DatabaseOps> {
+        let slots = KeySlots::load(path)?.expect("key slots");
+        DatabaseOps::open(path, Some(slots.unlock(passphrase)?.sqlcipher_key()))
+    }
+
+    #[test]
+    fn test_key_slots() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("db.sqlite");
+        let (db, data) = populated_db(&path, Some("alice".to_owned()))?;
+        drop(db);
+        assert!(list(&path).is_err());
+
+        // Converting to key slots re-encrypts the database with the master key.
+        assert!(add(&path, Some("eve".to_owned()), "bob", None, KDF).is_err());
+        assert!(!header_path(&path).exists());
+        assert_eq!(
+            add(&path, Some("alice".to_owned()), "bob", Some("Bob".to_owned()), KDF)?,
+            1
+        );
+        assert!(DatabaseOps::open(&path, Some("alice".to_owned())).is_err());
+        let mut out = Vec::new();
+        cat(&mut open(&path, "bob")?, Path::new("/src/random"), &mut out)?;
+        assert_eq!(out, data);
+        open(&path, "alice")?;
+
+        assert!(add(&path, Some("eve".to_owned()), "carol", None, KDF).is_err());
+        assert_eq!(add(&path, Some("bob".to_owned()), "carol", None, KDF)?, 2);
+        let slots = list(&path)?;
+        let labels: Vec<_> = slots.iter().map(|slot| (slot.id, slot.label.as_deref())).collect();
+        assert_eq!(labels, [(0, None), (1, Some("Bob")), (2, None)]);
+
+        assert!(remove(&path, "eve", 0).is_err());
+        remove(&path, "carol", 0)?;
+        assert!(open(&path, "alice").is_err());
+        open(&path, "carol")?;
+
+        // A plaintext database gets a single slot.
+        let plain = tmp.path().join("plain.sqlite");
+        DatabaseOps::create(&plain, None, &CreateOptions::default())?;
+        assert_eq!(add(&plain, None, "dave", None, KDF)?, 0);
+        assert!(DatabaseOps::open(&plain, None).is_err());
+        open(&plain, "dave")?;
+        Ok(())
+    }
+}
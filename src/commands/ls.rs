@@ -0,0 +1,251 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::database::DatabaseOps;
+use crate::errors::Result;
+use crate::queries;
+use crate::time::TimeSpec;
+
+/// Attributes of a file as printed by `ls` and `stat`.
+#[derive(Debug, Serialize)]
+pub struct Stat {
+    pub path: String,
+    pub ino: u64,
+    pub kind: &'static str,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub size: u64,
+    pub blocks: u64,
+    pub blksize: u32,
+    pub atime: TimeSpec,
+    pub mtime: TimeSpec,
+    pub ctime: TimeSpec,
+    pub crtime: TimeSpec,
+    /// Compression policy of the file or directory, see `set-compression --path`.
+    pub compression: Option<String>,
+}
+
+impl Stat {
+    fn lookup(tx: &mut rusqlite::Transaction, path: &Path, ino: u64) -> Result<Stat> {
+        let attr = queries::inode::lookup(tx, ino)?;
+        Ok(Stat {
+            path: path.to_string_lossy().into_owned(),
+            ino,
+            kind: kind_name(attr.kind),
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            size: attr.size,
+            blocks: attr.blocks,
+            blksize: attr.blksize,
+            atime: attr.atime.into(),
+            mtime: attr.mtime.into(),
+            ctime: attr.ctime.into(),
+            crtime: attr.crtime.into(),
+            compression: queries::inode::get_compression(tx, ino)?.map(|c| c.to_string()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self.path.rsplit_once('/') {
+            Some((_, "")) | None => &self.path,
+            Some((_, name)) => name,
+        }
+    }
+
+    /// Returns the permissions in the `drwxr-xr-x` notation of `ls -l`.
+    pub fn mode(&self) -> String {
+        let kind = match self.kind {
+            "directory" => 'd',
+            "symlink" => 'l',
+            "fifo" => 'p',
+            "char" => 'c',
+            "block" => 'b',
+            "socket" => 's',
+            _ => '-',
+        };
+        let mut mode = String::from(kind);
+        for (shift, special, set, unset) in [(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')] {
+            let bits = self.perm >> shift;
+            mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            mode.push(match (self.perm & special != 0, bits & 0o1 != 0) {
+                (true, true) => set,
+                (true, false) => unset,
+                (false, true) => 'x',
+                (false, false) => '-',
+            });
+        }
+        mode
+    }
+
+    /// Formats the entry like a line of `ls -l`.
+    pub fn long(&self, name: &str) -> String {
+        format!(
+            "{} {:>3} {:>5} {:>5} {:>10} {} {}",
+            self.mode(),
+            self.nlink,
+            self.uid,
+            self.gid,
+            self.size,
+            self.mtime,
+            name
+        )
+    }
+}
+
+/// Formats the attributes like `stat`.
+impl std::fmt::Display for Stat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  File: {}", self.path)?;
+        writeln!(f, " Inode: {:<10} Type: {}", self.ino, self.kind)?;
+        writeln!(
+            f,
+            "  Size: {:<10} Blocks: {:<10} IO Block: {}",
+            self.size, self.blocks, self.blksize
+        )?;
+        writeln!(
+            f,
+            "Access: ({:04o}/{})  Uid: {}  Gid: {}  Links: {}",
+            self.perm,
+            self.mode(),
+            self.uid,
+            self.gid,
+            self.nlink
+        )?;
+        if self.rdev != 0 {
+            writeln!(f, "Device: {}", self.rdev)?;
+        }
+        if let Some(compression) = &self.compression {
+            writeln!(f, "Compression: {}", compression)?;
+        }
+        writeln!(f, "Access: {}.{:09}", self.atime, self.atime.nanos)?;
+        writeln!(f, "Modify: {}.{:09}", self.mtime, self.mtime.nanos)?;
+        writeln!(f, "Change: {}.{:09}", self.ctime, self.ctime.nanos)?;
+        write!(f, " Birth: {}.{:09}", self.crtime, self.crtime.nanos)
+    }
+}
+
+fn kind_name(kind: fuser::FileType) -> &'static str {
+    match kind {
+        fuser::FileType::NamedPipe => "fifo",
+        fuser::FileType::CharDevice => "char",
+        fuser::FileType::BlockDevice => "block",
+        fuser::FileType::Directory => "directory",
+        fuser::FileType::RegularFile => "file",
+        fuser::FileType::Symlink => "symlink",
+        fuser::FileType::Socket => "socket",
+    }
+}
+
+/// Returns the entries of a directory sorted by name, as `(name, ino, is_dir)`.
+fn list(tx: &mut rusqlite::Transaction, ino: u64) -> Result<Vec<(OsString, u64, bool)>> {
+    let mut entries = Vec::new();
+    queries::dir_entry::list_dir(tx, ino, 0, |entry| {
+        entries.push((
+            entry.name.to_owned(),
+            entry.ino,
+            entry.kind == fuser::FileType::Directory,
+        ));
+        true
+    })?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn walk(tx: &mut rusqlite::Transaction, dir: &Path, ino: u64, recursive: bool, stats: &mut Vec<Stat>) -> Result<()> {
+    for (name, ino, is_dir) in list(tx, ino)? {
+        let path = dir.join(name);
+        stats.push(Stat::lookup(tx, &path, ino)?);
+        if recursive && is_dir {
+            walk(tx, &path, ino, recursive, stats)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the attributes of the file at `path`, resolved from the root directory.
+pub fn stat(db: &mut DatabaseOps, path: &Path) -> anyhow::Result<Stat> {
+    let stat = db.with_read_tx(|tx| {
+        let ino = queries::dir_entry::resolve(tx, path)?;
+        Stat::lookup(tx, path, ino)
+    })?;
+    Ok(stat)
+}
+
+/// Returns the entries of the directory at `path`, and of its subdirectories with `recursive`, or the file itself
+/// when `path` is not a directory. The entries of a subdirectory follow its own entry, like `find`.
+pub fn ls(db: &mut DatabaseOps, path: &Path, recursive: bool) -> anyhow::Result<Vec<Stat>> {
+    let stats = db.with_read_tx(|tx| {
+        let ino = queries::dir_entry::resolve(tx, path)?;
+        let stat = Stat::lookup(tx, path, ino)?;
+        if stat.kind != "directory" {
+            return Ok(vec![stat]);
+        }
+
+        let mut stats = Vec::new();
+        walk(tx, path, ino, recursive, &mut stats)?;
+        Ok(stats)
+    })?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use test_log::test;
+
+    use super::{ls, stat};
+    use crate::database::DatabaseOps;
+    use crate::queries;
+    use crate::test_util::create;
+    use crate::types::FileType;
+
+    #[test]
+    fn test_ls() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let root = create(&mut db, None, "", FileType::Directory, b"")?;
+        let b = create(&mut db, Some(root), "b", FileType::Directory, b"")?;
+        create(&mut db, Some(root), "a.txt", FileType::RegularFile, b"")?;
+        create(&mut db, Some(b), "c.txt", FileType::RegularFile, b"")?;
+        let d = create(&mut db, Some(b), "d", FileType::Directory, b"")?;
+        let e = create(&mut db, Some(d), "e", FileType::NamedPipe, b"")?;
+        db.with_write_tx(|tx| {
+            queries::inode::set_attr(tx, e, "perm", 0o4640)?;
+            queries::inode::set_attr(tx, e, "mtime_secs", 951782400)
+        })?;
+
+        let stats = ls(&mut db, Path::new("/"), false)?;
+        let paths: Vec<_> = stats.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, ["/a.txt", "/b"]);
+        assert!(ls(&mut db, Path::new("/missing"), false).is_err());
+
+        let stats = ls(&mut db, Path::new("/"), true)?;
+        let paths: Vec<_> = stats.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, ["/a.txt", "/b", "/b/c.txt", "/b/d", "/b/d/e"]);
+        assert_eq!(stats[1].mode(), "drwxr-xr-x");
+        assert_eq!(stats[2].name(), "c.txt");
+
+        let e = stat(&mut db, Path::new("/b/d/e"))?;
+        assert_eq!(e.kind, "fifo");
+        assert_eq!(e.mode(), "prwSr-----");
+        assert_eq!(e.mtime.to_string(), "2000-02-29 00:00:00");
+        assert!(e
+            .long("e")
+            .starts_with("prwSr-----   1     0     0          0 2000-02-29 00:00:00 e"));
+        assert_eq!(ls(&mut db, Path::new("/b/d/e"), true)?.len(), 1);
+
+        let json = serde_json::to_value(&e)?;
+        assert_eq!(json["path"], "/b/d/e");
+        assert_eq!(json["mtime"]["secs"], 951782400);
+        Ok(())
+    }
+}
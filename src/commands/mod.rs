@@ -0,0 +1,13 @@
+pub mod backup;
+pub mod fsck;
+pub mod get;
+pub mod key;
+pub mod ls;
+pub mod put;
+pub mod recompress;
+pub mod rekey;
+pub mod repack;
+pub mod scrub;
+pub mod sqlar;
+pub mod tar;
+pub mod train_dict;
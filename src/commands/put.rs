@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+
+use crate::database::DatabaseOps;
+use crate::driver::attr::FileAttrBuilder;
+use crate::driver::{FileHandle, OpenFlags};
+use crate::errors::{Error, Result};
+use crate::queries::{
+    self,
+    block::{Compression, CompressionSpec, Compressor},
+    dictionary::Dictionary,
+};
+use crate::time::TimeSpec;
+use crate::types::FileType;
+
+#[derive(Debug, Default)]
+pub struct PutStats {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+}
+
+/// Copies the host file or directory `src` to `dest` inside the filesystem, recursively. The parent of `dest` must
+/// exist; a directory copied onto an existing directory, the root included, is merged into it. Permissions, owners,
+/// timestamps, symlinks and hard links within `src` are preserved. Each file is written in its own transaction,
+/// `progress` is called after each of them.
+pub fn put(
+    db: &mut DatabaseOps,
+    src: &Path,
+    dest: &Path,
+    mut progress: impl FnMut(&PutStats),
+) -> anyhow::Result<PutStats> {
+    let writer = FileWriter::new(db)?;
+    let existing = db.with_read_tx(|tx| match queries::dir_entry::resolve(tx, dest) {
+        Ok(ino) => queries::inode::lookup(tx, ino).map(Some),
+        Err(Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    })?;
+
+    let mut importer = Importer {
+        db,
+        writer,
+        links: HashMap::new(),
+        stats: PutStats::default(),
+        progress: &mut progress,
+    };
+    match existing {
+        Some(attr) if attr.kind == fuser::FileType::Directory && fs::symlink_metadata(src)?.is_dir() => {
+            importer.import_children(src, attr.ino)?;
+        }
+        Some(_) => bail!("{} already exists", dest.display()),
+        None => {
+            let name = dest.file_name().context("destination must have a name")?;
+            let parent = dest.parent().context("destination must have a parent")?;
+            let parent = importer
+                .db
+                .with_read_tx(|tx| queries::dir_entry::resolve(tx, parent))
+                .with_context(|| format!("{} does not exist", parent.display()))?;
+            importer.import(src, parent, name)?;
+        }
+    }
+    Ok(importer.stats)
+}
+
+/// Compression settings of a database, used to write new files outside of the driver.
+pub struct FileWriter {
+    block_size: u64,
+    compression: CompressionSpec,
+    min_savings: u8,
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+impl FileWriter {
+    pub fn new(db: &mut DatabaseOps) -> anyhow::Result<FileWriter> {
+        let (block_size, compression) = (db.block_size()?, db.compression()?);
+        let (min_savings, dictionary) = db.with_read_tx(|tx| {
+            Ok((
+                queries::meta::min_savings(tx)?,
+                queries::dictionary::latest(tx)?.map(Arc::new),
+            ))
+        })?;
+        if compression.compression == Compression::ZstdDict && dictionary.is_none() {
+            bail!("Database has no zstd dictionary, run train-dict first");
+        }
+        Ok(FileWriter {
+            block_size,
+            compression,
+            min_savings,
+            dictionary,
+        })
+    }
+
+    /// Creates the inode `attr` as `name` in `parent` and writes the content of `data` to it. The compression policy is
+    /// inherited from `parent` unless `policy` is given. Returns the number of bytes written.
+    pub fn create(
+        &self,
+        tx: &mut rusqlite::Transaction,
+        parent: u64,
+        name: &OsStr,
+        attr: &mut fuser::FileAttr,
+        policy: Option<CompressionSpec>,
+        data: &mut dyn Read,
+    ) -> Result<u64> {
+        queries::inode::create(tx, attr)?;
+        match policy {
+            Some(policy) => {
+                // Blocks are written with it below, older versions must refuse the database.
+                queries::meta::enable_compression(tx, policy)?;
+                queries::inode::set_compression(tx, attr.ino, Some(policy))?;
+            }
+            None => queries::inode::inherit_compression(tx, parent, attr.ino)?,
+        }
+        queries::dir_entry::create(tx, parent, name, attr.ino)?;
+        let policy = queries::inode::get_compression(tx, attr.ino)?;
+        let compressor = Compressor::new(policy.unwrap_or(self.compression))
+            .with_min_savings(self.min_savings)
+            .with_dictionary(self.dictionary.clone());
+        let mut fh = FileHandle::new(attr.ino, 0, OpenFlags::from(0), self.block_size, compressor);
+        let mut buf = vec![0; 64 * 1024];
+        let mut written = 0;
+        loop {
+            let n = data.read(&mut buf).map_err(|e| Error::Other(e.to_string()))?;
+            if n == 0 {
+                break;
+            }
+            let mut chunk = &buf[..n];
+            while !chunk.is_empty() {
+                let consumed = fh.consume_input(chunk);
+                chunk = &chunk[consumed..];
+                if fh.buffer_full() {
+                    fh.flush(tx)?;
+                }
+            }
+            written += n as u64;
+        }
+        fh.flush(tx)?;
+        Ok(written)
+    }
+
+    /// Walks `dirs` from `parent`, creating the directories that do not exist yet. Returns the inode of the last one.
+    pub fn create_dirs(
+        &self,
+        tx: &mut rusqlite::Transaction,
+        mut parent: u64,
+        dirs: &[impl AsRef<OsStr>],
+    ) -> Result<u64> {
+        for dir in dirs {
+            let dir = dir.as_ref();
+            parent = match queries::dir_entry::lookup(tx, parent, dir) {
+                Ok(ino) => ino,
+                Err(Error::NotFound) => {
+                    let mut attr = FileAttrBuilder::new_directory().build();
+                    self.create(tx, parent, dir, &mut attr, None, &mut io::empty())?;
+                    attr.ino
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(parent)
+    }
+
+    /// Adds the entry `name` in `parent` for the existing inode `ino`.
+    pub fn link(&self, tx: &mut rusqlite::Transaction, ino: u64, parent: u64, name: &OsStr) -> Result<()> {
+        let nlink = queries::inode::lookup(tx, ino)?.nlink + 1;
+        queries::dir_entry::create(tx, parent, name, ino)?;
+        queries::inode::set_attr(tx, ino, "nlink", nlink)
+    }
+}
+
+struct Importer<'a, P: FnMut(&PutStats)> {
+    db: &'a mut DatabaseOps,
+    writer: FileWriter,
+    /// Inode created for the host files with more than one link, by host device and inode.
+    links: HashMap<(u64, u64), u64>,
+    stats: PutStats,
+    progress: &'a mut P,
+}
+
+impl<P: FnMut(&PutStats)> Importer<'_, P> {
+    fn import_children(&mut self, src: &Path, parent: u64) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(src)
+            .with_context(|| format!("unable to read {}", src.display()))?
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            self.import(&entry.path(), parent, &entry.file_name())?;
+        }
+        Ok(())
+    }
+
+    fn import(&mut self, src: &Path, parent: u64, name: &OsStr) -> anyhow::Result<()> {
+        let md = fs::symlink_metadata(src).with_context(|| format!("unable to read {}", src.display()))?;
+
+        if !md.is_dir() && md.nlink() > 1 {
+            if let Some(&ino) = self.links.get(&(md.dev(), md.ino())) {
+                let writer = &self.writer;
+                self.db.with_write_tx(|tx| writer.link(tx, ino, parent, name))?;
+                self.stats.files += 1;
+                (self.progress)(&self.stats);
+                return Ok(());
+            }
+        }
+
+        let file_type = md.file_type();
+        let kind = match file_type {
+            t if t.is_dir() => FileType::Directory,
+            t if t.is_file() => FileType::RegularFile,
+            t if t.is_symlink() => FileType::Symlink,
+            t if t.is_fifo() => FileType::NamedPipe,
+            t if t.is_char_device() => FileType::CharDevice,
+            t if t.is_block_device() => FileType::BlockDevice,
+            _ => FileType::Socket,
+        };
+        let builder = match kind {
+            FileType::Directory => FileAttrBuilder::new_directory(),
+            kind => FileAttrBuilder::new_node(kind),
+        };
+        let mtime = md.modified()?;
+        let mut attr = builder
+            .with_mode_umask(md.mode(), 0)
+            .with_uid(md.uid())
+            .with_gid(md.gid())
+            .with_rdev(md.rdev() as u32)
+            .with_times(
+                md.accessed()?,
+                mtime,
+                TimeSpec::new(md.ctime() as u64, md.ctime_nsec() as u32).into(),
+                md.created().unwrap_or(mtime),
+            )
+            .build();
+
+        let mut data: Box<dyn Read> = match kind {
+            FileType::RegularFile => {
+                Box::new(File::open(src).with_context(|| format!("unable to open {}", src.display()))?)
+            }
+            FileType::Symlink => Box::new(io::Cursor::new(fs::read_link(src)?.into_os_string().into_vec())),
+            _ => Box::new(io::empty()),
+        };
+        let writer = &self.writer;
+        let written = self
+            .db
+            .with_write_tx(|tx| writer.create(tx, parent, name, &mut attr, None, &mut data))
+            .with_context(|| format!("unable to import {}", src.display()))?;
+
+        match kind {
+            FileType::Directory => {
+                self.stats.directories += 1;
+                (self.progress)(&self.stats);
+                self.import_children(src, attr.ino)?;
+            }
+            _ => {
+                if md.nlink() > 1 {
+                    self.links.insert((md.dev(), md.ino()), attr.ino);
+                }
+                self.stats.files += 1;
+                self.stats.bytes += written;
+                (self.progress)(&self.stats);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use test_log::test;
+
+    use super::put;
+    use crate::commands::{get::get, ls::ls};
+    use crate::database::{CreateOptions, DatabaseOps};
+    use crate::test_util::sample_data;
+
+    #[test]
+    fn test_put() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("src");
+        let data = sample_data();
+        fs::create_dir_all(src.join("dir"))?;
+        fs::write(src.join("dir/big"), &data)?;
+        fs::write(src.join("small"), b"hello")?;
+        fs::hard_link(src.join("small"), src.join("dir/small"))?;
+        std::os::unix::fs::symlink("dir/big", src.join("link"))?;
+        let fifo = CString::new(src.join("fifo").as_os_str().as_bytes())?;
+        // SAFETY: fifo is a valid NUL terminated string.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+        fs::set_permissions(src.join("small"), fs::Permissions::from_mode(0o600))?;
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options()
+            .write(true)
+            .open(src.join("small"))?
+            .set_modified(mtime)?;
+
+        let mut db = DatabaseOps::create(&tmp.path().join("db.sqlite"), None, &CreateOptions::default())?;
+        let mut calls = 0;
+        let stats = put(&mut db, &src, Path::new("/imported"), |_| calls += 1)?;
+        assert_eq!(
+            (stats.files, stats.directories, stats.bytes),
+            (5, 2, data.len() as u64 + 5 + 7)
+        );
+        assert_eq!(calls, 7);
+        assert!(put(&mut db, &src.join("small"), Path::new("/imported/small"), |_| {}).is_err());
+
+        let stats = ls(&mut db, Path::new("/imported"), true)?;
+        let paths: Vec<_> = stats.iter().map(|s| (s.path.as_str(), s.kind, s.nlink)).collect();
+        assert_eq!(
+            paths,
+            [
+                ("/imported/dir", "directory", 2),
+                ("/imported/dir/big", "file", 1),
+                ("/imported/dir/small", "file", 2),
+                ("/imported/fifo", "fifo", 1),
+                ("/imported/link", "symlink", 1),
+                ("/imported/small", "file", 2),
+            ]
+        );
+        assert_eq!(stats[2].ino, stats[5].ino);
+        assert_eq!((stats[5].perm, stats[5].mtime.secs), (0o600, 1_000_000_000));
+        assert_eq!(stats[3].perm, 0o640);
+
+        let out = tmp.path().join("out");
+        get(&mut db, Path::new("/imported"), &out)?;
+        assert_eq!(fs::read(out.join("dir/big"))?, data);
+        assert_eq!(fs::read_link(out.join("link"))?, Path::new("dir/big"));
+        let meta = fs::metadata(out.join("small"))?;
+        assert_eq!((meta.mode() & 0o7777, meta.mtime()), (0o600, 1_000_000_000));
+        assert_eq!(meta.ino(), fs::metadata(out.join("dir/small"))?.ino());
+
+        // Copying a directory onto an existing one merges them.
+        fs::remove_dir_all(&src)?;
+        fs::create_dir(&src)?;
+        fs::write(src.join("new"), b"new")?;
+        put(&mut db, &src, Path::new("/imported"), |_| {})?;
+        assert_eq!(ls(&mut db, Path::new("/imported"), false)?.len(), 5);
+
+        // Including into the root.
+        put(&mut db, &src, Path::new("/"), |_| {})?;
+        let names: Vec<_> = ls(&mut db, Path::new("/"), false)?
+            .into_iter()
+            .map(|s| s.path)
+            .collect();
+        assert_eq!(names, ["/imported", "/new"]);
+        assert!(put(&mut db, &src.join("new"), Path::new("/"), |_| {}).is_err());
+        assert!(put(&mut db, &src, Path::new("/missing/dir"), |_| {}).is_err());
+        Ok(())
+    }
+}
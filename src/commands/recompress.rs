@@ -0,0 +1,243 @@
+use std::sync::Arc;
+
+use crate::database::DatabaseOps;
+use crate::queries::{
+    self,
+    block::{CompressionSpec, Compressor},
+};
+
+/// Last item rewritten by a recompress, stored in the meta table so that an interrupted recompress resumes where it
+/// stopped. Inline data is rewritten first, then blocks in `(ino, bno)` order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cursor {
+    Inline(u64),
+    Block(u64, u64),
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cursor::Inline(ino) => write!(f, "inline:{ino}"),
+            Cursor::Block(ino, bno) => write!(f, "block:{ino}:{bno}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.split(':').collect();
+        match parts[..] {
+            ["inline", ino] => Ok(Cursor::Inline(ino.parse().map_err(|_| ())?)),
+            ["block", ino, bno] => Ok(Cursor::Block(
+                ino.parse().map_err(|_| ())?,
+                bno.parse().map_err(|_| ())?,
+            )),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RecompressStats {
+    /// Blocks and inline files rewritten so far.
+    pub done: u64,
+    /// Blocks and inline files left when the recompress started or resumed.
+    pub total: u64,
+    /// Stored size of the rewritten data before and after recompression.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Rewrites every block and inline file with `compression`, `batch_size` at a time in separate transactions.
+/// `progress` is called after each batch. Once done, `compression` becomes the default compression of the database.
+pub fn recompress(
+    db: &mut DatabaseOps,
+    compression: CompressionSpec,
+    batch_size: usize,
+    mut progress: impl FnMut(&RecompressStats),
+) -> anyhow::Result<RecompressStats> {
+    let block_size = db.block_size()?;
+    let target = compression.to_string();
+
+    let (mut cursor, dictionary, min_savings) = db.with_write_tx(|tx| {
+        queries::meta::enable_compression(tx, compression)?;
+        let previous: Option<String> = queries::meta::get(tx, queries::meta::RECOMPRESS_TARGET)?;
+        let cursor: Option<String> = queries::meta::get(tx, queries::meta::RECOMPRESS_CURSOR)?;
+        let cursor = match cursor.map(|c| c.parse()) {
+            Some(Ok(cursor)) if previous.as_ref() == Some(&target) => {
+                log::info!("Resuming recompress to {} after {}", target, cursor);
+                cursor
+            }
+            _ => {
+                queries::meta::set(tx, queries::meta::RECOMPRESS_TARGET, &target)?;
+                Cursor::Inline(0)
+            }
+        };
+        let dictionary = queries::dictionary::latest(tx)?.map(Arc::new);
+        Ok((cursor, dictionary, queries::meta::min_savings(tx)?))
+    })?;
+
+    let mut stats = RecompressStats {
+        total: db.with_read_tx(|tx| match cursor {
+            Cursor::Inline(ino) => {
+                Ok(queries::inode::count_inline_after(tx, ino)? + queries::block::count_after(tx, 0, 0)?)
+            }
+            Cursor::Block(ino, bno) => queries::block::count_after(tx, ino, bno),
+        })?,
+        ..Default::default()
+    };
+    let mut compressor = Compressor::new(compression)
+        .with_min_savings(min_savings)
+        .with_dictionary(dictionary);
+    // `Compression::Auto` picks the compression of a file from its first block.
+    let mut last_ino = None;
+
+    loop {
+        let next = db.with_write_tx(|tx| {
+            let next = match cursor {
+                Cursor::Inline(ino) => {
+                    let inos = queries::inode::list_inline_after(tx, ino, batch_size)?;
+                    for &(ino, len) in &inos {
+                        compressor.start_file();
+                        if let Some(block) = queries::inode::get_inline(tx, ino, block_size)? {
+                            stats.bytes_before += len;
+                            stats.bytes_after += queries::inode::set_inline(tx, &block, &mut compressor)?;
+                            stats.done += 1;
+                        }
+                    }
+                    Some(inos.last().map_or(Cursor::Block(0, 0), |&(ino, _)| Cursor::Inline(ino)))
+                }
+                Cursor::Block(ino, bno) => {
+                    let blocks = queries::block::list_after(tx, ino, bno, batch_size)?;
+                    for &(ino, bno, len) in &blocks {
+                        if last_ino != Some(ino) {
+                            compressor.start_file();
+                            last_ino = Some(ino);
+                        }
+                        let block = queries::block::get_block(tx, ino, bno, block_size)?;
+                        stats.bytes_before += len;
+                        stats.bytes_after += queries::block::update(tx, &block, &mut compressor)?;
+                        stats.done += 1;
+                    }
+                    blocks.last().map(|&(ino, bno, _)| Cursor::Block(ino, bno))
+                }
+            };
+            match next {
+                Some(next) => queries::meta::set(tx, queries::meta::RECOMPRESS_CURSOR, next.to_string())?,
+                None => {
+                    queries::meta::delete(tx, queries::meta::RECOMPRESS_CURSOR)?;
+                    queries::meta::delete(tx, queries::meta::RECOMPRESS_TARGET)?;
+                    queries::meta::set_compression(tx, compression)?;
+                }
+            }
+            Ok(next)
+        })?;
+        progress(&stats);
+        match next {
+            Some(next) => cursor = next,
+            None => break,
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::{recompress, Cursor};
+    use crate::database::DatabaseOps;
+    use crate::queries;
+    use crate::queries::block::{Compression, DEFAULT_BLOCK_SIZE};
+    use crate::test_util::{create, media_data, sample_data, text_data};
+    use crate::types::FileType;
+
+    fn compressions(db: &mut DatabaseOps) -> anyhow::Result<Vec<u8>> {
+        let compressions = db.with_read_tx(|tx| {
+            let mut stmt = tx.prepare("SELECT compression FROM block ORDER BY ino, bno")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<Vec<u8>>>()?)
+        })?;
+        Ok(compressions)
+    }
+
+    #[test]
+    fn test_recompress() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = sample_data();
+
+        let mut inos = Vec::new();
+        for len in [100, 300 * 1024, 200 * 1024] {
+            inos.push(create(&mut db, None, "", FileType::RegularFile, &data[..len])?);
+        }
+        assert_eq!(compressions(&mut db)?, vec![Compression::LZ4 as u8; 5]);
+
+        // Pretend a previous recompress to zstd stopped after the first block of the second file.
+        db.with_write_tx(|tx| {
+            queries::meta::set(tx, queries::meta::RECOMPRESS_TARGET, "zstd")?;
+            queries::meta::set(
+                tx,
+                queries::meta::RECOMPRESS_CURSOR,
+                Cursor::Block(inos[1], 0).to_string(),
+            )
+        })?;
+        let mut batches = 0;
+        let stats = recompress(&mut db, Compression::Zstd.into(), 2, |_| batches += 1)?;
+        assert_eq!((stats.done, stats.total), (4, 4));
+        assert_eq!(batches, 3);
+        assert!(stats.bytes_after < stats.bytes_before);
+        let expected = [
+            Compression::LZ4,
+            Compression::Zstd,
+            Compression::Zstd,
+            Compression::Zstd,
+            Compression::Zstd,
+        ];
+        assert_eq!(compressions(&mut db)?, expected.map(|c| c as u8));
+        assert_eq!(db.compression()?, Compression::Zstd.into());
+
+        // A new target starts over, inline data included.
+        let stats = recompress(&mut db, "zstd:19".parse().unwrap(), 100, |_| {})?;
+        assert_eq!((stats.done, stats.total), (6, 6));
+        assert_eq!(compressions(&mut db)?, vec![Compression::Zstd as u8; 5]);
+        assert!(db
+            .with_read_tx(|tx| queries::meta::get::<String>(tx, queries::meta::RECOMPRESS_CURSOR))?
+            .is_none());
+
+        for (ino, len) in inos.into_iter().zip([100, 300 * 1024, 200 * 1024]) {
+            let mut read = Vec::new();
+            db.with_read_tx(|tx| {
+                if let Some(block) = queries::inode::get_inline(tx, ino, DEFAULT_BLOCK_SIZE)? {
+                    read.extend_from_slice(&block.data);
+                }
+                queries::block::iter_blocks_from(tx, ino, 0, DEFAULT_BLOCK_SIZE, |block| {
+                    read.extend_from_slice(&block.data);
+                    Ok(true)
+                })
+            })?;
+            assert_eq!(read, data[..len]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recompress_auto() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let text = text_data();
+        let media = media_data(text.len());
+
+        for data in [&text, &media] {
+            create(&mut db, None, "", FileType::RegularFile, data)?;
+        }
+
+        // Each file gets its own pick, not the one of the first block recompressed.
+        recompress(&mut db, "auto".parse().unwrap(), 100, |_| {})?;
+        let mut compressions = compressions(&mut db)?;
+        compressions.dedup();
+        assert_eq!(compressions, [Compression::Zstd as u8, Compression::LZ4 as u8]);
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::cipher::CipherSettings;
+use crate::commands::backup::{backup, BackupKey, BackupSteps};
+use crate::database::DatabaseOps;
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Opens the database at `path` with `key` and checks its pages and their HMAC.
+fn verify(path: &Path, key: Option<String>) -> anyhow::Result<()> {
+    let mut db = DatabaseOps::open(path, key)?;
+    let mut problems = db.integrity_check()?;
+    problems.extend(db.cipher_integrity_check()?);
+    if !problems.is_empty() {
+        bail!("{}", problems.join(", "));
+    }
+    Ok(())
+}
+
+/// Changes the encryption of the database at `path` from `key` to `new_key`, `None` meaning plaintext. The database
+/// must not be mounted. Changing the key uses `PRAGMA rekey` on a copy of the database, kept until the rekeyed
+/// database is verified, and keeps the cipher settings. Encrypting or decrypting exports the database to a new file with
+/// `sqlcipher_export`, which replaces the original once verified, with the default cipher settings.
+pub fn rekey(path: &Path, key: Option<String>, new_key: Option<String>) -> anyhow::Result<()> {
+    match (&key, &new_key) {
+        (None, None) => bail!("Database is not encrypted"),
+        (key, new_key) if key == new_key => bail!("The new key is the current key"),
+        _ => {}
+    }
+
+    let mut db = DatabaseOps::open(path, key.clone()).context("open db")?;
+    // Leaving WAL mode needs the only connection to the database, so this fails while it is mounted. The rollback
+    // journal also keeps the rekey atomic.
+    db.db
+        .pragma_update_and_check(None, "journal_mode", "DELETE", |row| row.get::<_, String>(0))
+        .context("Database is in use, unmount it first")?;
+    let all = BackupSteps {
+        pages: None,
+        pause: Duration::ZERO,
+    };
+
+    match (key.is_some(), new_key) {
+        (true, Some(new_key)) => {
+            let sqlcipher_key = db.cipher.sqlcipher_key(new_key.clone())?;
+            let copy = sibling(path, ".rekey-backup");
+            backup(&mut db, key, &copy, BackupKey::Same, all, |_| {})?;
+            db.db.pragma_update(None, "rekey", sqlcipher_key).context("rekey")?;
+            drop(db);
+            verify(path, Some(new_key)).with_context(|| {
+                format!(
+                    "Rekeyed database is damaged, the original is kept in {}",
+                    copy.display()
+                )
+            })?;
+            fs::remove_file(&copy)?;
+            CipherSettings::default().save(&copy)?;
+        }
+        (_, new_key) => {
+            let exported = sibling(path, ".rekey");
+            let backup_key = new_key.clone().map_or(BackupKey::Plaintext, BackupKey::Key);
+            backup(&mut db, key, &exported, backup_key, all, |_| {})?;
+            drop(db);
+            verify(&exported, new_key)?;
+            fs::rename(&exported, path)?;
+            CipherSettings::default().save(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use test_log::test;
+
+    use super::rekey;
+    use crate::commands::get::cat;
+    use crate::database::DatabaseOps;
+    use crate::test_util::populated_db;
+
+    #[test]
+    fn test_rekey() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("db.sqlite");
+        let old = Some("old".to_owned());
+        let new = Some("new".to_owned());
+        let (db, data) = populated_db(&path, old.clone())?;
+        // Not while another connection uses it.
+        assert!(rekey(&path, old.clone(), new.clone()).is_err());
+        drop(db);
+        assert!(rekey(&path, old.clone(), old.clone()).is_err());
+        assert!(rekey(&path, None, None).is_err());
+
+        let check = |key: Option<String>| -> anyhow::Result<()> {
+            let mut db = DatabaseOps::open(&path, key)?;
+            let mut out = Vec::new();
+            cat(&mut db, Path::new("/src/random"), &mut out)?;
+            assert_eq!(out, data);
+            Ok(())
+        };
+        rekey(&path, old.clone(), new.clone())?;
+        assert!(DatabaseOps::open(&path, old.clone()).is_err());
+        check(new.clone())?;
+        // The copy kept during the rekey is removed.
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+
+        rekey(&path, new.clone(), None)?;
+        assert!(DatabaseOps::open(&path, new.clone()).is_err());
+        check(None)?;
+
+        rekey(&path, None, old.clone())?;
+        assert!(DatabaseOps::open(&path, None).is_err());
+        check(old)?;
+        Ok(())
+    }
+}
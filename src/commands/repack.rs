@@ -0,0 +1,188 @@
+use std::{cmp, sync::Arc};
+
+use anyhow::Context;
+
+use crate::database::DatabaseOps;
+use crate::errors::Result;
+use crate::queries::{
+    self,
+    block::{Block, CompressionSpec, Compressor},
+};
+
+/// Rewrites the blocks of every file using `block_size` and records it as the block size of the database. The new blocks
+/// of each file are staged in a transaction of their own, then replace the old ones of every file at once along with
+/// the block size. An interrupted repack leaves the database untouched, its staged blocks are discarded by the next
+/// one. The database must not be mounted.
+pub fn repack(db: &mut DatabaseOps, block_size: u64, compression: CompressionSpec) -> anyhow::Result<usize> {
+    queries::block::validate_block_size(block_size).context("invalid block size")?;
+
+    let (old_block_size, inos, mut compressor) = db.with_write_tx(|tx| {
+        queries::block::discard_staged(tx)?;
+        queries::meta::enable_compression(tx, compression)?;
+        let dictionary = queries::dictionary::latest(tx)?.map(Arc::new);
+        let compressor = Compressor::new(compression)
+            .with_min_savings(queries::meta::min_savings(tx)?)
+            .with_dictionary(dictionary);
+        Ok((
+            queries::meta::block_size(tx)?,
+            queries::block::list_inos(tx)?,
+            compressor,
+        ))
+    })?;
+    for (i, &ino) in inos.iter().enumerate() {
+        db.with_write_tx(|tx| stage_file(tx, ino, old_block_size, block_size, &mut compressor))?;
+        log::info!("Repacked ino {} ({}/{})", ino, i + 1, inos.len());
+    }
+    db.with_write_tx(|tx| {
+        for &ino in &inos {
+            queries::block::commit_staged(tx, ino)?;
+        }
+        queries::meta::set(tx, queries::meta::BLOCK_SIZE, block_size)
+    })?;
+    Ok(inos.len())
+}
+
+fn stage_file(
+    tx: &mut rusqlite::Transaction,
+    ino: u64,
+    old_block_size: u64,
+    block_size: u64,
+    compressor: &mut Compressor,
+) -> Result<()> {
+    let zeros = vec![0u8; block_size as usize];
+    let mut out = Block::empty(ino, 0, block_size);
+    compressor.start_file();
+
+    for bno in queries::block::list_bnos(tx, ino)? {
+        let block = queries::block::get_block(tx, ino, bno, old_block_size)?;
+
+        // Sparse files can have missing blocks. Zeros only fill the new blocks around the data, the blocks within the
+        // hole stay missing.
+        let start = block.start_offset();
+        let mut offset = out.start_offset() + out.data.len() as u64;
+        if offset < start && !out.data.is_empty() {
+            let len = cmp::min(start - offset, block_size - out.data.len() as u64);
+            write_staged(tx, &mut out, &zeros[..len as usize], compressor)?;
+            offset += len;
+        }
+        if offset < start {
+            out = Block::empty(ino, start / block_size, block_size);
+            let len = start - out.start_offset();
+            write_staged(tx, &mut out, &zeros[..len as usize], compressor)?;
+        }
+
+        write_staged(tx, &mut out, &block.data, compressor)?;
+    }
+
+    if !out.data.is_empty() {
+        queries::block::create_staged(tx, &out, compressor)?;
+    }
+    Ok(())
+}
+
+fn write_staged(
+    tx: &mut rusqlite::Transaction,
+    out: &mut Block,
+    mut data: &[u8],
+    compressor: &mut Compressor,
+) -> Result<()> {
+    while !data.is_empty() {
+        let written = out.consume(data);
+        data = &data[written as usize..];
+        if out.data.len() as u64 == out.block_size {
+            queries::block::create_staged(tx, out, compressor)?;
+            *out = Block::empty(out.ino, out.bno + 1, out.block_size);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::repack;
+    use crate::database::DatabaseOps;
+    use crate::queries;
+    use crate::queries::block::Compression;
+    use crate::test_util::{create, media_data, sample_data, text_data};
+    use crate::types::FileType;
+
+    #[test]
+    fn test_repack() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = sample_data();
+
+        let ino = create(&mut db, None, "", FileType::RegularFile, &data)?;
+
+        assert_eq!(repack(&mut db, 16 * 1024, Compression::Zstd.into())?, 1);
+        assert_eq!(db.block_size()?, 16 * 1024);
+
+        let mut read = Vec::new();
+        let mut block_count = 0;
+        db.with_read_tx(|tx| {
+            queries::block::iter_blocks_from(tx, ino, 0, 16 * 1024, |block| {
+                assert_eq!(block.bno, block_count);
+                block_count += 1;
+                read.extend_from_slice(&block.data);
+                Ok(true)
+            })
+        })?;
+        assert_eq!(block_count, 19);
+        assert_eq!(read, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repack_sparse() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = sample_data();
+        let ino = create(&mut db, None, "", FileType::RegularFile, &data)?;
+        // Moves the last block far away, leaving a hole of 97 blocks, and stages a block as an interrupted repack.
+        db.with_write_tx(|tx| {
+            tx.execute("UPDATE block SET bno = 100 WHERE ino = ? AND bno = 2", [ino])?;
+            tx.execute(
+                "INSERT INTO block (ino, bno, data, compression) SELECT ino, -1, data, compression FROM block WHERE bno = 0",
+                [],
+            )?;
+            Ok(())
+        })?;
+
+        assert_eq!(repack(&mut db, 16 * 1024, Compression::Zstd.into())?, 1);
+        let mut blocks = Vec::new();
+        db.with_read_tx(|tx| {
+            queries::block::iter_blocks_from(tx, ino, 0, 16 * 1024, |block| {
+                blocks.push((block.bno, block.data.clone()));
+                Ok(true)
+            })
+        })?;
+        let bnos: Vec<u64> = blocks.iter().map(|(bno, _)| *bno).collect();
+        assert_eq!(bnos, (0..16).chain(800..803).collect::<Vec<_>>());
+        let read: Vec<u8> = blocks.into_iter().flat_map(|(_, data)| data).collect();
+        assert_eq!(read, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repack_auto() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let text = text_data();
+        let media = media_data(text.len());
+
+        for data in [&text, &media] {
+            create(&mut db, None, "", FileType::RegularFile, data)?;
+        }
+
+        // Each file gets its own pick, not the one of the first file repacked.
+        assert_eq!(repack(&mut db, 64 * 1024, "auto".parse().unwrap())?, 2);
+        let mut compressions = db.with_read_tx(|tx| {
+            let mut stmt = tx.prepare("SELECT compression FROM block ORDER BY ino, bno")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<Vec<u8>>>()?)
+        })?;
+        compressions.dedup();
+        assert_eq!(compressions, [Compression::Zstd as u8, Compression::LZ4 as u8]);
+        Ok(())
+    }
+}
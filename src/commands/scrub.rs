@@ -0,0 +1,232 @@
+use serde::Serialize;
+
+use crate::database::DatabaseOps;
+use crate::errors::Error;
+use crate::queries;
+
+/// Blocks and inline files verified per read transaction, so that a mounted filesystem can still write in between.
+const BATCH_SIZE: usize = 256;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Problem {
+    /// The data cannot be decompressed, or does not match its checksum. `bno` is `None` for inline data.
+    Corrupted { ino: u64, bno: Option<u64> },
+    /// The data extends past the size of its inode.
+    BeyondSize {
+        ino: u64,
+        bno: Option<u64>,
+        end: u64,
+        size: u64,
+    },
+    /// The block belongs to an inode that does not exist.
+    MissingInode { ino: u64, bno: u64 },
+    /// A problem reported by `PRAGMA integrity_check`.
+    Integrity { message: String },
+    /// A page reported by `PRAGMA cipher_integrity_check`.
+    CipherIntegrity { message: String },
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ScrubReport {
+    /// Blocks and inline files verified.
+    pub blocks: u64,
+    pub inline: u64,
+    /// Decompressed size of the verified data.
+    pub bytes: u64,
+    pub problems: Vec<Problem>,
+}
+
+/// Decompresses every block and inline file of the database and checks them against the size of their inode, then
+/// verifies the database file itself. Nothing is modified, corrupted files are reported for the user to restore.
+pub fn scrub(db: &mut DatabaseOps) -> anyhow::Result<ScrubReport> {
+    let block_size = db.block_size()?;
+    let mut report = ScrubReport::default();
+
+    let mut cursor = 0;
+    loop {
+        let inos = db.with_read_tx(|tx| {
+            let inos = queries::inode::list_inline_after(tx, cursor, BATCH_SIZE)?;
+            for &(ino, _) in &inos {
+                let size = queries::inode::get_size(tx, ino)?;
+                let len = match queries::inode::get_inline(tx, ino, block_size) {
+                    Ok(block) => block.map_or(0, |block| block.data.len() as u64),
+                    Err(Error::Corrupted | Error::InvalidCompression) => {
+                        report.problems.push(Problem::Corrupted { ino, bno: None });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                report.inline += 1;
+                report.bytes += len;
+                if len > size {
+                    report.problems.push(Problem::BeyondSize {
+                        ino,
+                        bno: None,
+                        end: len,
+                        size,
+                    });
+                }
+            }
+            Ok(inos)
+        })?;
+        match inos.last() {
+            Some(&(ino, _)) => cursor = ino,
+            None => break,
+        }
+    }
+
+    let mut cursor = (0, 0);
+    loop {
+        let blocks = db.with_read_tx(|tx| {
+            let blocks = queries::block::list_after(tx, cursor.0, cursor.1, BATCH_SIZE)?;
+            for &(ino, bno, _) in &blocks {
+                let size = match queries::inode::get_size(tx, ino) {
+                    Ok(size) => size,
+                    Err(Error::NotFound) => {
+                        report.problems.push(Problem::MissingInode { ino, bno });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let block = match queries::block::get_block(tx, ino, bno, block_size) {
+                    Ok(block) => block,
+                    Err(Error::Corrupted | Error::InvalidCompression) => {
+                        report.problems.push(Problem::Corrupted { ino, bno: Some(bno) });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                report.blocks += 1;
+                report.bytes += block.data.len() as u64;
+                let end = bno * block_size + block.data.len() as u64;
+                if end > size {
+                    report.problems.push(Problem::BeyondSize {
+                        ino,
+                        bno: Some(bno),
+                        end,
+                        size,
+                    });
+                }
+            }
+            Ok(blocks)
+        })?;
+        match blocks.last() {
+            Some(&(ino, bno, _)) => cursor = (ino, bno),
+            None => break,
+        }
+    }
+
+    for message in db.integrity_check()? {
+        report.problems.push(Problem::Integrity { message });
+    }
+    for message in db.cipher_integrity_check()? {
+        report.problems.push(Problem::CipherIntegrity { message });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use std::sync::Arc;
+
+    use super::{scrub, Problem};
+    use crate::database::DatabaseOps;
+    use crate::queries::{
+        self,
+        block::{Compression, Compressor, DEFAULT_BLOCK_SIZE},
+    };
+    use crate::test_util::{create, create_with, sample_data, text_data};
+    use crate::types::FileType;
+
+    #[test]
+    fn test_scrub() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = sample_data();
+
+        let mut inos = Vec::new();
+        for len in [100, 300 * 1024, 200 * 1024] {
+            inos.push(create(&mut db, None, "", FileType::RegularFile, &data[..len])?);
+        }
+
+        let report = scrub(&mut db)?;
+        assert_eq!((report.inline, report.blocks), (1, 5));
+        assert_eq!(report.bytes, 100 + 500 * 1024);
+        assert!(report.problems.is_empty());
+
+        db.db.execute_batch(&format!(
+            "UPDATE inode SET size = 50 WHERE ino = {};
+            UPDATE block SET data = x'deadbeef' WHERE ino = {} AND bno = 1;
+            UPDATE inode SET size = 1000 WHERE ino = {};",
+            inos[0], inos[1], inos[2]
+        ))?;
+        let report = scrub(&mut db)?;
+        let expected = vec![
+            Problem::BeyondSize {
+                ino: inos[0],
+                bno: None,
+                end: 100,
+                size: 50,
+            },
+            Problem::Corrupted {
+                ino: inos[1],
+                bno: Some(1),
+            },
+            Problem::BeyondSize {
+                ino: inos[2],
+                bno: Some(0),
+                end: DEFAULT_BLOCK_SIZE,
+                size: 1000,
+            },
+            Problem::BeyondSize {
+                ino: inos[2],
+                bno: Some(1),
+                end: 200 * 1024,
+                size: 1000,
+            },
+        ];
+        assert_eq!(report.problems, expected);
+        assert_eq!(
+            serde_json::to_value(&report.problems[1])?,
+            serde_json::json!({"problem": "corrupted", "ino": inos[1], "bno": 1})
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrub_missing_dictionary() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let data = text_data();
+        let dictionary = db.with_write_tx(|tx| {
+            let id = queries::dictionary::create(tx, &data[..4096])?;
+            queries::dictionary::get(tx, id)
+        })?;
+        let dictionary = Arc::new(dictionary);
+        let compressor = || Compressor::new(Compression::ZstdDict.into()).with_dictionary(Some(dictionary.clone()));
+        let inline = create_with(&mut db, None, "", FileType::RegularFile, &data[..100], compressor())?;
+        let blocks = create_with(&mut db, None, "", FileType::RegularFile, &data, compressor())?;
+
+        db.db.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+            DELETE FROM dictionary;
+            PRAGMA foreign_keys = ON;",
+        )?;
+        let report = scrub(&mut db)?;
+        let expected = vec![
+            Problem::Corrupted { ino: inline, bno: None },
+            Problem::Corrupted {
+                ino: blocks,
+                bno: Some(0),
+            },
+            Problem::Corrupted {
+                ino: blocks,
+                bno: Some(1),
+            },
+        ];
+        assert_eq!(report.problems, expected);
+        Ok(())
+    }
+}
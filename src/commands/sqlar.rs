@@ -0,0 +1,333 @@
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+use rusqlite::{params, types::ValueRef, OpenFlags};
+
+use crate::commands::{get::read_file, put::FileWriter, tar::archive_path};
+use crate::database::DatabaseOps;
+use crate::driver::attr::FileAttrBuilder;
+use crate::errors::{Error, Result};
+use crate::queries;
+use crate::time::TimeSpec;
+use crate::types::FileType;
+
+/// Largest expansion of zlib, a `sz` above it cannot come from the compressed data.
+const ZLIB_MAX_RATIO: i64 = 1032;
+
+/// Schema of the archives of `sqlite3 -A`.
+const SQLAR_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS sqlar(
+    name TEXT PRIMARY KEY,  -- name of the file
+    mode INT,               -- access permissions
+    mtime INT,              -- last modification time
+    sz INT,                 -- original file size
+    data BLOB               -- compressed content
+)";
+
+#[derive(Debug, Default)]
+pub struct SqlarStats {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+}
+
+/// Compresses `data` like `sqlite3 -A`: with zlib, unless that does not make it smaller.
+fn compress(data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    let compressed = encoder
+        .write_all(&data)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| Error::Other(e.to_string()))?;
+    Ok(if compressed.len() < data.len() {
+        compressed
+    } else {
+        data
+    })
+}
+
+/// Writes the whole filesystem to the `sqlar` table of the SQLite database `out`, created if needed, so that it can be
+/// listed and extracted with `sqlite3 -A`. Entries already in the archive with the same names are replaced, like
+/// `sqlite3 -Au` does. The format only holds directories, regular files and symlinks: hard links are written as
+/// separate copies, device nodes, fifos and sockets are skipped. Files are compressed in memory.
+pub fn export_sqlar(db: &mut DatabaseOps, out: &Path) -> anyhow::Result<SqlarStats> {
+    let mut archive = rusqlite::Connection::open(out).with_context(|| format!("unable to open {}", out.display()))?;
+    let archive_tx = archive.transaction()?;
+    archive_tx.execute(SQLAR_SCHEMA, [])?;
+
+    let block_size = db.block_size()?;
+    let mut stats = SqlarStats::default();
+    let mut insert = archive_tx.prepare("REPLACE INTO sqlar (name, mode, mtime, sz, data) VALUES (?, ?, ?, ?, ?)")?;
+    db.with_read_tx(|tx| {
+        let mut pending = vec![(String::new(), 1)];
+        while let Some((dir, ino)) = pending.pop() {
+            let mut entries: Vec<(OsString, u64)> = Vec::new();
+            queries::dir_entry::list_dir(tx, ino, 0, |entry| {
+                entries.push((entry.name.to_owned(), entry.ino));
+                true
+            })?;
+            entries.sort();
+            for (name, ino) in entries.into_iter().rev() {
+                let Some(name) = name.to_str() else {
+                    log::warn!("Skipping {}/{}: sqlar names must be UTF-8", dir, name.to_string_lossy());
+                    continue;
+                };
+                let name = if dir.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{dir}/{name}")
+                };
+                let attr = queries::inode::lookup(tx, ino)?;
+                let (kind, size, data) = match attr.kind {
+                    fuser::FileType::Directory => {
+                        pending.push((name.clone(), ino));
+                        stats.directories += 1;
+                        (libc::S_IFDIR, 0, None)
+                    }
+                    fuser::FileType::RegularFile => {
+                        let mut data = Vec::new();
+                        read_file(tx, ino, attr.size, block_size, &mut data)?;
+                        stats.files += 1;
+                        stats.bytes += attr.size;
+                        (libc::S_IFREG, attr.size as i64, Some(compress(data)?))
+                    }
+                    fuser::FileType::Symlink => {
+                        let mut target = Vec::new();
+                        read_file(tx, ino, attr.size, block_size, &mut target)?;
+                        stats.files += 1;
+                        (libc::S_IFLNK, -1, Some(target))
+                    }
+                    _ => {
+                        log::warn!("Skipping {}: sqlar has no special files", name);
+                        continue;
+                    }
+                };
+                let mtime = TimeSpec::from(attr.mtime).secs;
+                insert.execute(params![name, kind | u32::from(attr.perm), mtime, size, data])?;
+            }
+        }
+        Ok(())
+    })?;
+    drop(insert);
+    archive_tx.commit()?;
+    Ok(stats)
+}
+
+/// Creates the entries of the `sqlar` table of the SQLite database `src` under the directory `dest` of the filesystem.
+/// Missing parent directories are created, existing directories are merged and any other existing file is an error.
+/// Each entry is written in its own transaction, `progress` is called after each of them.
+pub fn import_sqlar(
+    db: &mut DatabaseOps,
+    src: &Path,
+    dest: &Path,
+    mut progress: impl FnMut(&SqlarStats),
+) -> anyhow::Result<SqlarStats> {
+    let writer = FileWriter::new(db)?;
+    let archive = rusqlite::Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("unable to open {}", src.display()))?;
+    let dest_ino = db.with_read_tx(|tx| queries::dir_entry::resolve(tx, dest))?;
+
+    let mut stats = SqlarStats::default();
+    let mut select = archive.prepare("SELECT name, mode, mtime, sz, data FROM sqlar ORDER BY name")?;
+    let mut rows = select.query([])?;
+    while let Some(row) = rows.next()? {
+        let (name, mode, mtime, size): (String, u32, u64, i64) = (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+        // `sqlite3 -A` stores symlink targets as text.
+        let data = match row.get_ref(4)? {
+            ValueRef::Blob(data) | ValueRef::Text(data) => data.to_vec(),
+            _ => Vec::new(),
+        };
+        let Some(components) = archive_path(name.as_bytes()) else {
+            log::warn!("Skipping {}: path outside of the archive", name);
+            continue;
+        };
+        let Some((file_name, parents)) = components.split_last() else {
+            continue;
+        };
+
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFDIR => FileType::Directory,
+            _ if size == -1 => FileType::Symlink,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFREG | 0 => FileType::RegularFile,
+            _ => {
+                log::warn!("Skipping {}: unsupported mode {:o}", name, mode);
+                continue;
+            }
+        };
+        // Checked before decompressing, the archive could be corrupt or made to exhaust the memory or the disk.
+        let compressed = matches!(kind, FileType::RegularFile) && (data.len() as i64) < size;
+        if matches!(kind, FileType::RegularFile)
+            && (size < 0 || compressed && size > (data.len() as i64).saturating_mul(ZLIB_MAX_RATIO))
+        {
+            bail!("{}: invalid size {} for {} bytes of data", name, size, data.len());
+        }
+        // Decompressed while written, one more byte than expected is enough to tell that the size is wrong.
+        let mut content: Box<dyn Read> = if compressed {
+            Box::new(ZlibDecoder::new(data.as_slice()).take(size as u64 + 1))
+        } else {
+            Box::new(data.as_slice())
+        };
+
+        let builder = match kind {
+            FileType::Directory => FileAttrBuilder::new_directory(),
+            kind => FileAttrBuilder::new_node(kind),
+        };
+        let mtime = TimeSpec::new(mtime, 0).into();
+        let mut attr = builder
+            .with_mode_umask(mode, 0)
+            .with_times(mtime, mtime, mtime, mtime)
+            .build();
+        let written = db
+            .with_write_tx(|tx| {
+                let parent = writer.create_dirs(tx, dest_ino, parents)?;
+                match queries::dir_entry::lookup(tx, parent, file_name) {
+                    Ok(ino) => {
+                        let existing = queries::inode::lookup(tx, ino)?;
+                        if attr.kind != fuser::FileType::Directory || existing.kind != fuser::FileType::Directory {
+                            return Err(Error::AlreadyExists);
+                        }
+                        queries::inode::set_attr(tx, ino, "perm", attr.perm)?;
+                        Ok(0)
+                    }
+                    Err(Error::NotFound) => {
+                        let written = writer.create(tx, parent, file_name, &mut attr, None, &mut content)?;
+                        if compressed && written as i64 != size {
+                            return Err(Error::Other(format!("expected {size} bytes, found {written}")));
+                        }
+                        Ok(written)
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+            .with_context(|| format!("unable to import {}", name))?;
+
+        match kind {
+            FileType::Directory => stats.directories += 1,
+            _ => {
+                stats.files += 1;
+                if matches!(kind, FileType::RegularFile) {
+                    stats.bytes += written;
+                }
+            }
+        }
+        progress(&stats);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    use rusqlite::params;
+    use test_log::test;
+
+    use super::{compress, export_sqlar, import_sqlar, SQLAR_SCHEMA};
+    use crate::commands::{get::cat, ls::ls, put::FileWriter};
+    use crate::database::{CreateOptions, DatabaseOps};
+    use crate::driver::attr::FileAttrBuilder;
+    use crate::test_util::sample_data;
+    use crate::types::FileType;
+
+    #[test]
+    fn test_sqlar_round_trip() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data = sample_data();
+        let random: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+
+        let mut db = DatabaseOps::create(&tmp.path().join("src.sqlite"), None, &CreateOptions::default())?;
+        let writer = FileWriter::new(&mut db)?;
+        db.with_write_tx(|tx| {
+            let mut attr = FileAttrBuilder::new_directory().with_mode_umask(0o750, 0).build();
+            writer.create(tx, 1, OsStr::new("dir"), &mut attr, None, &mut &b""[..])?;
+            let dir = attr.ino;
+            let mut attr = FileAttrBuilder::new_node(FileType::RegularFile).build();
+            writer.create(tx, dir, OsStr::new("big"), &mut attr, None, &mut &data[..])?;
+            let mut attr = FileAttrBuilder::new_node(FileType::RegularFile).build();
+            writer.create(tx, 1, OsStr::new("random"), &mut attr, None, &mut &random[..])?;
+            let mut attr = FileAttrBuilder::new_node(FileType::Symlink).build();
+            writer.create(tx, 1, OsStr::new("link"), &mut attr, None, &mut &b"dir/big"[..])?;
+            let mut attr = FileAttrBuilder::new_node(FileType::NamedPipe).build();
+            writer.create(tx, 1, OsStr::new("fifo"), &mut attr, None, &mut &b""[..])?;
+            Ok(())
+        })?;
+
+        let archive = tmp.path().join("out.sqlar");
+        let stats = export_sqlar(&mut db, &archive)?;
+        assert_eq!((stats.files, stats.directories), (3, 1));
+        // Exporting again replaces the entries.
+        export_sqlar(&mut db, &archive)?;
+
+        // The layout read by `sqlite3 -A`: zlib data smaller than `sz`, raw data otherwise, -1 for symlinks.
+        let conn = rusqlite::Connection::open(&archive)?;
+        let rows: Vec<(String, u32, i64, usize)> = conn
+            .prepare("SELECT name, mode, sz, ifnull(length(data), 0) FROM sqlar ORDER BY name")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(rows[0], ("dir".to_owned(), libc::S_IFDIR | 0o750, 0, 0));
+        assert_eq!((rows[1].0.as_str(), rows[1].2), ("dir/big", data.len() as i64));
+        assert!(rows[1].3 < data.len());
+        assert_eq!(rows[2], ("link".to_owned(), libc::S_IFLNK | 0o644, -1, 7));
+        assert_eq!(rows[3].3, random.len());
+        conn.execute("INSERT INTO sqlar VALUES ('text', 41471, 0, -1, 'dir')", [])?;
+        drop(conn);
+
+        let mut copy = DatabaseOps::create(&tmp.path().join("copy.sqlite"), None, &CreateOptions::default())?;
+        let mut calls = 0;
+        let stats = import_sqlar(&mut copy, &archive, Path::new("/"), |_| calls += 1)?;
+        assert_eq!((stats.files, stats.directories, calls), (4, 1, 5));
+        let entries = ls(&mut copy, Path::new("/"), true)?;
+        let paths: Vec<_> = entries.iter().map(|s| (s.path.as_str(), s.kind, s.perm)).collect();
+        assert_eq!(
+            paths,
+            [
+                ("/dir", "directory", 0o750),
+                ("/dir/big", "file", 0o644),
+                ("/link", "symlink", 0o644),
+                ("/random", "file", 0o644),
+                ("/text", "symlink", 0o777),
+            ]
+        );
+        let mut out = Vec::new();
+        cat(&mut copy, Path::new("/dir/big"), &mut out)?;
+        assert_eq!(out, data);
+        out.clear();
+        cat(&mut copy, Path::new("/random"), &mut out)?;
+        assert_eq!(out, random);
+
+        assert!(import_sqlar(&mut copy, &archive, Path::new("/"), |_| {}).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlar_invalid_size() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let archive = tmp.path().join("bad.sqlar");
+        let conn = rusqlite::Connection::open(&archive)?;
+        conn.execute(SQLAR_SCHEMA, [])?;
+        let zeros = compress(vec![0; 100 * 1024])?;
+        let mut insert = conn.prepare("INSERT INTO sqlar VALUES (?, 33188, 0, ?, ?)")?;
+        for (name, size) in [
+            ("negative", -5),
+            ("huge", i64::MAX),
+            ("short", 1000 * 1024),
+            ("long", 1024),
+        ] {
+            insert.execute(params![name, size, zeros])?;
+        }
+        drop(insert);
+
+        // Imported in name order, each error stops the import before the next entry.
+        let mut db = DatabaseOps::create(&tmp.path().join("db.sqlite"), None, &CreateOptions::default())?;
+        for name in ["huge", "long", "negative", "short"] {
+            let err = import_sqlar(&mut db, &archive, Path::new("/"), |_| {}).unwrap_err();
+            assert!(format!("{err:#}").contains(name), "{err:#}");
+            conn.execute("DELETE FROM sqlar WHERE name = ?", [name])?;
+        }
+        assert!(ls(&mut db, Path::new("/"), true)?.is_empty());
+        Ok(())
+    }
+}
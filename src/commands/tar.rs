@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use tar::{EntryType, Header};
+
+use crate::commands::{get::read_file, put::FileWriter};
+use crate::database::DatabaseOps;
+use crate::driver::attr::FileAttrBuilder;
+use crate::driver::XATTR_COMPRESSION;
+use crate::errors::{Error, Result};
+use crate::queries::{self, block::CompressionSpec};
+use crate::time::TimeSpec;
+use crate::types::FileType;
+
+/// Prefix of the PAX records holding extended attributes, as written by GNU tar and bsdtar.
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+#[derive(Debug, Default)]
+pub struct TarStats {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+}
+
+fn io_error(err: io::Error) -> Error {
+    Error::Other(err.to_string())
+}
+
+fn is_zstd(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("zst"))
+}
+
+fn pax_time(time: SystemTime) -> Vec<u8> {
+    let time = TimeSpec::from(time);
+    format!("{}.{:09}", time.secs, time.nanos).into_bytes()
+}
+
+/// Copies the start of `value` to a header field, when the whole value is written as a PAX record.
+fn truncate_into(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+fn parse_pax_time(value: &[u8]) -> Option<SystemTime> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let digits = fraction.get(..fraction.len().min(9))?;
+    let nanos = if digits.is_empty() {
+        0
+    } else {
+        digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32)
+    };
+    Some(TimeSpec::new(secs.parse().ok()?, nanos).into())
+}
+
+/// Writes the whole filesystem to the tar archive `out`, compressed with zstd when its name ends with `.zst`. Names
+/// and link targets that do not fit a ustar header, timestamps with nanoseconds and compression policies (as the
+/// `user.nightshift.compression` extended attribute) are written as PAX records.
+pub fn export_tar(db: &mut DatabaseOps, out: &Path) -> anyhow::Result<TarStats> {
+    let file = File::create(out).with_context(|| format!("unable to create {}", out.display()))?;
+    let (mut file, stats) = if is_zstd(out) {
+        let (encoder, stats) = write_tar(db, zstd::Encoder::new(file, 0)?)?;
+        // Writes the end of the zstd stream, which dropping the encoder would do ignoring errors.
+        (encoder.finish()?, stats)
+    } else {
+        write_tar(db, file)?
+    };
+    file.flush()?;
+    Ok(stats)
+}
+
+/// Writes the tar archive of the whole filesystem to `writer`, returned once the archive is complete.
+fn write_tar<W: Write>(db: &mut DatabaseOps, writer: W) -> anyhow::Result<(W, TarStats)> {
+    let mut exporter = Exporter {
+        builder: tar::Builder::new(BufWriter::new(writer)),
+        block_size: db.block_size()?,
+        links: HashMap::new(),
+        stats: TarStats::default(),
+    };
+    db.with_read_tx(|tx| exporter.export_children(tx, 1, Path::new("")))?;
+    let writer = exporter
+        .builder
+        .into_inner()?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    Ok((writer, exporter.stats))
+}
+
+struct Exporter<W: Write> {
+    builder: tar::Builder<W>,
+    block_size: u64,
+    /// Archive path of the files written so far that have more than one link, by inode.
+    links: HashMap<u64, Vec<u8>>,
+    stats: TarStats,
+}
+
+impl<W: Write> Exporter<W> {
+    fn export_children(&mut self, tx: &mut rusqlite::Transaction, ino: u64, dir: &Path) -> Result<()> {
+        let mut entries: Vec<(OsString, u64)> = Vec::new();
+        queries::dir_entry::list_dir(tx, ino, 0, |entry| {
+            entries.push((entry.name.to_owned(), entry.ino));
+            true
+        })?;
+        entries.sort();
+        for (name, ino) in entries {
+            self.export(tx, ino, &dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    fn export(&mut self, tx: &mut rusqlite::Transaction, ino: u64, path: &Path) -> Result<()> {
+        let attr = queries::inode::lookup(tx, ino)?;
+        let mut header = Header::new_ustar();
+        let mut pax: Vec<(String, Vec<u8>)> = Vec::new();
+        if header.set_path(path).is_err() {
+            pax.push(("path".to_owned(), path.as_os_str().as_bytes().to_vec()));
+            truncate_into(&mut header.as_old_mut().name, path.as_os_str().as_bytes());
+        }
+        header.set_mode(u32::from(attr.perm));
+        header.set_uid(u64::from(attr.uid));
+        header.set_gid(u64::from(attr.gid));
+        header.set_mtime(TimeSpec::from(attr.mtime).secs);
+        header.set_size(0);
+        for (key, time) in [("atime", attr.atime), ("mtime", attr.mtime), ("ctime", attr.ctime)] {
+            pax.push((key.to_owned(), pax_time(time)));
+        }
+        if let Some(policy) = queries::inode::get_compression(tx, ino)? {
+            pax.push((
+                format!("{PAX_XATTR}{XATTR_COMPRESSION}"),
+                policy.to_string().into_bytes(),
+            ));
+        }
+
+        let hard_link = self.links.get(&ino).cloned();
+        let mut link = hard_link.clone();
+        match hard_link {
+            Some(_) => header.set_entry_type(EntryType::Link),
+            None => match attr.kind {
+                fuser::FileType::Directory => header.set_entry_type(EntryType::Directory),
+                fuser::FileType::RegularFile => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(attr.size);
+                }
+                fuser::FileType::Symlink => {
+                    header.set_entry_type(EntryType::Symlink);
+                    let mut target = Vec::new();
+                    read_file(tx, ino, attr.size, self.block_size, &mut target)?;
+                    link = Some(target);
+                }
+                fuser::FileType::NamedPipe => header.set_entry_type(EntryType::Fifo),
+                fuser::FileType::CharDevice | fuser::FileType::BlockDevice => {
+                    header.set_entry_type(match attr.kind {
+                        fuser::FileType::CharDevice => EntryType::Char,
+                        _ => EntryType::Block,
+                    });
+                    let rdev = attr.rdev as libc::dev_t;
+                    header.set_device_major(libc::major(rdev)).map_err(io_error)?;
+                    header.set_device_minor(libc::minor(rdev)).map_err(io_error)?;
+                }
+                fuser::FileType::Socket => {
+                    log::warn!("Skipping socket {}", path.display());
+                    return Ok(());
+                }
+            },
+        }
+        if let Some(target) = &link {
+            if header.set_link_name_literal(target).is_err() {
+                pax.push(("linkpath".to_owned(), target.clone()));
+                truncate_into(&mut header.as_old_mut().linkname, target);
+            }
+        }
+        header.set_cksum();
+
+        let builder = &mut self.builder;
+        builder
+            .append_pax_extensions(pax.iter().map(|(key, value)| (key.as_str(), value.as_slice())))
+            .map_err(io_error)?;
+        // The data is streamed from the database after the header, then padded to the 512 bytes records of tar.
+        builder.append(&header, io::empty()).map_err(io_error)?;
+        let size = header.size().map_err(io_error)?;
+        if size > 0 {
+            read_file(tx, ino, size, self.block_size, builder.get_mut())?;
+            let padding = (512 - size % 512) % 512;
+            builder
+                .get_mut()
+                .write_all(&[0; 512][..padding as usize])
+                .map_err(io_error)?;
+        }
+
+        if attr.kind == fuser::FileType::Directory {
+            self.stats.directories += 1;
+            return self.export_children(tx, ino, path);
+        }
+        if attr.nlink > 1 && hard_link.is_none() {
+            self.links.insert(ino, path.as_os_str().as_bytes().to_vec());
+        }
+        self.stats.files += 1;
+        self.stats.bytes += size;
+        Ok(())
+    }
+}
+
+/// Returns the normal components of an archive path, or `None` when it leaves the extraction directory.
+pub fn archive_path(path: &[u8]) -> Option<Vec<&OsStr>> {
+    let mut components = Vec::new();
+    for component in Path::new(OsStr::from_bytes(path)).components() {
+        match component {
+            Component::Normal(name) => components.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(components)
+}
+
+/// Creates the entries of the tar archive `src` under the directory `dest` of the filesystem, decompressing it with
+/// zstd when its name ends with `.zst`. Missing parent directories are created, existing directories are merged and
+/// any other existing file is an error. Each entry is written in its own transaction, `progress` is called after each
+/// of them.
+pub fn import_tar(
+    db: &mut DatabaseOps,
+    src: &Path,
+    dest: &Path,
+    mut progress: impl FnMut(&TarStats),
+) -> anyhow::Result<TarStats> {
+    let writer = FileWriter::new(db)?;
+    let file = File::open(src).with_context(|| format!("unable to open {}", src.display()))?;
+    let reader: Box<dyn Read> = if is_zstd(src) {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    let dest_ino = db.with_read_tx(|tx| queries::dir_entry::resolve(tx, dest))?;
+
+    let mut stats = TarStats::default();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path_bytes().into_owned();
+        let display = String::from_utf8_lossy(&path).into_owned();
+        let Some(components) = archive_path(&path) else {
+            log::warn!("Skipping {}: path outside of the archive", display);
+            continue;
+        };
+        let Some((name, parents)) = components.split_last() else {
+            continue;
+        };
+        let (name, parents) = (
+            name.to_os_string(),
+            parents.iter().map(PathBuf::from).collect::<Vec<_>>(),
+        );
+
+        let header = entry.header().clone();
+        let entry_type = header.entry_type();
+        let kind = match entry_type {
+            EntryType::Regular | EntryType::Continuous => FileType::RegularFile,
+            EntryType::Directory => FileType::Directory,
+            EntryType::Symlink => FileType::Symlink,
+            EntryType::Link => FileType::RegularFile,
+            EntryType::Fifo => FileType::NamedPipe,
+            EntryType::Char => FileType::CharDevice,
+            EntryType::Block => FileType::BlockDevice,
+            other => {
+                log::warn!("Skipping {}: unsupported entry type {:?}", display, other);
+                continue;
+            }
+        };
+        let link = entry.link_name_bytes().map(|link| link.into_owned());
+
+        let mut times = HashMap::new();
+        let mut policy = None;
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                let key = extension.key().unwrap_or_default();
+                match key.strip_prefix(PAX_XATTR) {
+                    Some(XATTR_COMPRESSION) => {
+                        let value = std::str::from_utf8(extension.value_bytes())?;
+                        let spec: CompressionSpec = value
+                            .parse()
+                            .map_err(|_| anyhow::anyhow!("{}: invalid compression {}", display, value))?;
+                        policy = Some(spec);
+                    }
+                    Some(xattr) => log::warn!("Skipping extended attribute {} of {}", xattr, display),
+                    None => {
+                        if let Some(time) = parse_pax_time(extension.value_bytes()) {
+                            times.insert(key.to_owned(), time);
+                        }
+                    }
+                }
+            }
+        }
+        let mtime = times
+            .get("mtime")
+            .copied()
+            .unwrap_or_else(|| TimeSpec::new(header.mtime().unwrap_or(0), 0).into());
+        let builder = match kind {
+            FileType::Directory => FileAttrBuilder::new_directory(),
+            kind => FileAttrBuilder::new_node(kind),
+        };
+        let rdev = match kind {
+            FileType::CharDevice | FileType::BlockDevice => {
+                libc::makedev(header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0)) as u32
+            }
+            _ => 0,
+        };
+        let mut attr = builder
+            .with_mode_umask(header.mode()?, 0)
+            .with_uid(header.uid()? as u32)
+            .with_gid(header.gid()? as u32)
+            .with_rdev(rdev)
+            .with_times(
+                times.get("atime").copied().unwrap_or(mtime),
+                mtime,
+                times.get("ctime").copied().unwrap_or(mtime),
+                mtime,
+            )
+            .build();
+
+        let written = db
+            .with_write_tx(|tx| {
+                let parent = writer.create_dirs(tx, dest_ino, &parents)?;
+                let existing = match queries::dir_entry::lookup(tx, parent, &name) {
+                    Ok(ino) => Some(queries::inode::lookup(tx, ino)?),
+                    Err(Error::NotFound) => None,
+                    Err(e) => return Err(e),
+                };
+                match (existing, entry_type) {
+                    (Some(existing), EntryType::Directory) if existing.kind == fuser::FileType::Directory => {
+                        for (column, value) in [("perm", u32::from(attr.perm)), ("uid", attr.uid), ("gid", attr.gid)] {
+                            queries::inode::set_attr(tx, existing.ino, column, value)?;
+                        }
+                        if policy.is_some() {
+                            queries::inode::set_compression(tx, existing.ino, policy)?;
+                        }
+                        Ok(0)
+                    }
+                    (Some(_), _) => Err(Error::AlreadyExists),
+                    (None, EntryType::Link) => {
+                        let target = link.as_deref().ok_or(Error::InvalidArgument)?;
+                        let target = archive_path(target).ok_or(Error::InvalidArgument)?;
+                        let ino = queries::dir_entry::resolve(tx, &dest.join(target.iter().collect::<PathBuf>()))?;
+                        writer.link(tx, ino, parent, &name)?;
+                        Ok(0)
+                    }
+                    (None, EntryType::Symlink) => {
+                        let mut target = link.as_deref().unwrap_or_default();
+                        writer.create(tx, parent, &name, &mut attr, policy, &mut target)
+                    }
+                    (None, _) => writer.create(tx, parent, &name, &mut attr, policy, &mut entry),
+                }
+            })
+            .with_context(|| format!("unable to import {}", display))?;
+
+        match kind {
+            FileType::Directory => stats.directories += 1,
+            _ => {
+                stats.files += 1;
+                stats.bytes += written;
+            }
+        }
+        progress(&stats);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    use test_log::test;
+
+    use super::{export_tar, import_tar};
+    use crate::commands::{get::cat, ls::ls};
+    use crate::database::{CreateOptions, DatabaseOps};
+    use crate::driver::attr::FileAttrBuilder;
+    use crate::queries::{self, block::Compression};
+    use crate::test_util::sample_data;
+    use crate::types::FileType;
+
+    #[test]
+    fn test_tar_round_trip() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data = sample_data();
+        let long_name = "n".repeat(150);
+
+        let mut db = DatabaseOps::create(&tmp.path().join("src.sqlite"), None, &CreateOptions::default())?;
+        let writer = super::FileWriter::new(&mut db)?;
+        db.with_write_tx(|tx| {
+            let mut attr = FileAttrBuilder::new_directory().with_mode_umask(0o750, 0).build();
+            writer.create(
+                tx,
+                1,
+                OsStr::new("dir"),
+                &mut attr,
+                Some(Compression::Xz.into()),
+                &mut &b""[..],
+            )?;
+            let dir = attr.ino;
+            let mut attr = FileAttrBuilder::new_node(FileType::RegularFile).build();
+            writer.create(tx, dir, OsStr::new(&long_name), &mut attr, None, &mut &data[..])?;
+            writer.link(tx, attr.ino, 1, OsStr::new("hard"))?;
+            let mut attr = FileAttrBuilder::new_node(FileType::Symlink).build();
+            writer.create(tx, 1, OsStr::new("link"), &mut attr, None, &mut &b"dir"[..])?;
+            let mut attr = FileAttrBuilder::new_node(FileType::CharDevice)
+                .with_rdev(libc::makedev(1, 3) as u32)
+                .build();
+            writer.create(tx, 1, OsStr::new("null"), &mut attr, None, &mut &b""[..])?;
+            Ok(())
+        })?;
+
+        for archive in ["out.tar", "out.tar.zst"] {
+            let archive = tmp.path().join(archive);
+            let stats = export_tar(&mut db, &archive)?;
+            assert_eq!((stats.files, stats.directories, stats.bytes), (4, 1, data.len() as u64));
+
+            let path = tmp.path().join(format!("{}.sqlite", archive.display()));
+            let mut copy = DatabaseOps::create(&path, None, &CreateOptions::default())?;
+            let mut calls = 0;
+            import_tar(&mut copy, &archive, Path::new("/"), |_| calls += 1)?;
+            assert_eq!(calls, 5);
+
+            let stats = ls(&mut copy, Path::new("/"), true)?;
+            let paths: Vec<_> = stats.iter().map(|s| (s.name(), s.kind, s.nlink)).collect();
+            assert_eq!(
+                paths,
+                [
+                    ("dir", "directory", 2),
+                    (long_name.as_str(), "file", 2),
+                    ("hard", "file", 2),
+                    ("link", "symlink", 1),
+                    ("null", "char", 1),
+                ]
+            );
+            assert_eq!(stats[0].perm, 0o750);
+            assert_eq!(stats[0].compression.as_deref(), Some("xz"));
+            assert_eq!(stats[1].compression.as_deref(), Some("xz"));
+            // The xz blocks of the copy are not readable by versions without the feature.
+            assert!(copy.with_read_tx(queries::meta::features)?.contains(&"xz".to_owned()));
+            assert_eq!(stats[1].ino, stats[2].ino);
+            assert_eq!(stats[4].rdev, libc::makedev(1, 3) as u32);
+
+            let mut out = Vec::new();
+            cat(&mut copy, Path::new("/hard"), &mut out)?;
+            assert_eq!(out, data);
+            let original = ls(&mut db, Path::new("/"), true)?;
+            assert_eq!(
+                (stats[1].mtime.secs, stats[1].mtime.nanos),
+                (original[1].mtime.secs, original[1].mtime.nanos)
+            );
+
+            // Importing again conflicts with the existing files.
+            assert!(import_tar(&mut copy, &archive, Path::new("/"), |_| {}).is_err());
+        }
+        Ok(())
+    }
+}
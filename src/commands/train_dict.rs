@@ -0,0 +1,88 @@
+use anyhow::{bail, Context};
+
+use crate::database::DatabaseOps;
+use crate::queries;
+
+/// Blocks are cut into samples of this size, zstd trains better on many small samples than on a few large ones.
+const SAMPLE_SIZE: usize = 16 * 1024;
+
+/// Trains a zstd dictionary on up to `samples` random blocks and inline files, and stores it in the database. New
+/// blocks written with `zstd-dict` compression use the most recently trained dictionary.
+pub fn train_dict(db: &mut DatabaseOps, samples: usize, max_size: usize) -> anyhow::Result<(u64, usize)> {
+    let block_size = db.block_size()?;
+    let mut data = db.with_read_tx(|tx| {
+        let mut data = Vec::new();
+        for (ino, bno) in queries::block::sample(tx, samples)? {
+            let block = queries::block::get_block(tx, ino, bno, block_size)?;
+            data.extend(block.data.chunks(SAMPLE_SIZE).map(<[u8]>::to_vec));
+        }
+        for ino in queries::inode::sample_inline(tx, samples)? {
+            if let Some(block) = queries::inode::get_inline(tx, ino, block_size)? {
+                data.push(block.data);
+            }
+        }
+        Ok(data)
+    })?;
+
+    data.retain(|sample| !sample.is_empty());
+    if data.is_empty() {
+        bail!("Database has no data to train a dictionary on");
+    }
+    log::info!("Training dictionary on {} samples", data.len());
+    let dictionary = zstd::dict::from_samples(&data, max_size).context("unable to train dictionary")?;
+
+    let id = db.with_write_tx(|tx| {
+        let id = queries::dictionary::create(tx, &dictionary)?;
+        queries::meta::enable_feature(tx, "zstd_dict")?;
+        Ok(id)
+    })?;
+    Ok((id, dictionary.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use test_log::test;
+
+    use super::train_dict;
+    use crate::database::DatabaseOps;
+    use crate::queries;
+    use crate::queries::block::{Compression, Compressor, DEFAULT_BLOCK_SIZE};
+    use crate::test_util::{create, create_with};
+    use crate::types::FileType;
+
+    #[test]
+    fn test_train_dict() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        assert!(train_dict(&mut db, 100, 4096).is_err());
+
+        for i in 0..200 {
+            let data = format!("{{\"id\": {i}, \"name\": \"user-{i}\", \"email\": \"user{i}@example.com\"}}\n");
+            create(
+                &mut db,
+                None,
+                "",
+                FileType::RegularFile,
+                data.repeat(i % 7 + 1).as_bytes(),
+            )?;
+        }
+
+        let (id, len) = train_dict(&mut db, 1000, 4096)?;
+        assert!(len > 0 && len <= 4096);
+        assert!(db
+            .with_read_tx(queries::meta::features)?
+            .contains(&"zstd_dict".to_owned()));
+
+        let dictionary = db.with_read_tx(queries::dictionary::latest)?.unwrap();
+        assert_eq!(dictionary.id, id);
+        let compressor = Compressor::new(Compression::ZstdDict.into()).with_dictionary(Some(Arc::new(dictionary)));
+        let data = b"{\"id\": 1000, \"name\": \"user-1000\", \"email\": \"user1000@example.com\"}\n".repeat(100);
+        let ino = create_with(&mut db, None, "", FileType::RegularFile, &data, compressor)?;
+
+        let block = db.with_read_tx(|tx| queries::block::get_block(tx, ino, 0, DEFAULT_BLOCK_SIZE))?;
+        assert_eq!(block.data, data);
+
+        Ok(())
+    }
+}
@@ -1,35 +1,159 @@
-use std::{collections::BTreeMap, path::Path, sync::LazyLock};
+use std::{collections::BTreeMap, path::Path, sync::LazyLock, time::SystemTime};
 
+use crate::cipher::{settings_path, CipherSettings};
+use crate::driver::attr::FileAttrBuilder;
 use crate::errors::Result;
+use crate::queries::{self, block::CompressionSpec};
+use crate::time::TimeSpec;
 use anyhow::Context;
-use rusqlite::params;
+use rusqlite::{params, OpenFlags};
 
 static MIGRATIONS: LazyLock<BTreeMap<u32, &'static str>> = LazyLock::new(|| {
     let mut m = BTreeMap::new();
     m.insert(1, include_str!("migrations/001_initial_tables.sql"));
     m.insert(2, include_str!("migrations/002_block_compression.sql"));
+    m.insert(3, include_str!("migrations/003_inline_data.sql"));
+    m.insert(4, include_str!("migrations/004_meta.sql"));
+    m.insert(5, include_str!("migrations/005_superblock.sql"));
+    m.insert(6, include_str!("migrations/006_dictionaries.sql"));
+    m.insert(7, include_str!("migrations/007_compression_policy.sql"));
+    m.insert(8, include_str!("migrations/008_block_checksum.sql"));
     m
 });
 
 pub struct DatabaseOps {
     pub(crate) db: rusqlite::Connection,
+    /// Settings the database was decrypted with, the defaults for plaintext databases.
+    pub(crate) cipher: CipherSettings,
+}
+
+/// Settings of a new database. They are stored in the `meta` table and cannot be changed by mount options.
+#[derive(Debug)]
+pub struct CreateOptions {
+    pub block_size: u64,
+    pub compression: CompressionSpec,
+    pub min_savings: u8,
+    pub label: Option<String>,
+    /// Kept next to the database rather than in `meta`, which cannot be read before decrypting it.
+    pub cipher: CipherSettings,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            block_size: queries::block::DEFAULT_BLOCK_SIZE,
+            compression: CompressionSpec::default(),
+            min_savings: queries::block::DEFAULT_MIN_SAVINGS,
+            label: None,
+            cipher: CipherSettings::default(),
+        }
+    }
 }
 
 impl DatabaseOps {
+    /// Opens the database at `path`, decrypted with `key` and the cipher settings saved next to it.
     pub fn open(path: &Path, key: Option<String>) -> anyhow::Result<Self> {
-        let mut db = rusqlite::Connection::open(path).context("open")?;
-        if let Some(key) = key {
-            set_cipher_key(&db, key)?;
+        Self::open_with(path, key, CipherSettings::load(path)?)
+    }
+
+    /// Opens the database at `path`, decrypted with `key` and `cipher` whatever settings are saved next to it.
+    pub fn open_with(path: &Path, key: Option<String>, cipher: CipherSettings) -> anyhow::Result<Self> {
+        // Without CREATE, so that a mistyped path is not left behind as an empty database.
+        if !path.exists() {
+            anyhow::bail!("No such database: {}", path.display());
         }
+        Self::connect(path, key, cipher, OpenFlags::SQLITE_OPEN_READ_WRITE)
+    }
+
+    fn connect(path: &Path, key: Option<String>, cipher: CipherSettings, flags: OpenFlags) -> anyhow::Result<Self> {
+        let flags = flags | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let mut db = rusqlite::Connection::open_with_flags(path, flags).context("open")?;
+        let cipher = match key {
+            Some(key) => {
+                set_cipher_key(&db, key, &cipher)?;
+                cipher
+            }
+            None => {
+                check_readable(&db)?;
+                CipherSettings::default()
+            }
+        };
         migrate_database(&mut db)?;
-        Ok(DatabaseOps { db })
+        let mut ops = DatabaseOps { db, cipher };
+        ops.check_features()?;
+        let uuid: Option<String> = ops.with_read_tx(|tx| queries::meta::get(tx, queries::meta::UUID))?;
+        log::info!("Opened filesystem {}", uuid.unwrap_or_default());
+        Ok(ops)
+    }
+
+    /// Opens the database at `path`, creating it with `compression` as its default when the file is absent or empty, as
+    /// when mounting a database that was never initialized.
+    pub fn open_or_create(
+        path: &Path,
+        key: Option<String>,
+        compression: Option<CompressionSpec>,
+    ) -> anyhow::Result<Self> {
+        let fresh = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len() == 0,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e).with_context(|| format!("unable to read {}", path.display())),
+        };
+        if !fresh {
+            return Self::open(path, key);
+        }
+        let options = CreateOptions {
+            compression: compression.unwrap_or_default(),
+            ..Default::default()
+        };
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Self::create(path, key, &options)
+    }
+
+    /// Creates a new database with the given options. Fails if the file already exists.
+    pub fn create(path: &Path, key: Option<String>, options: &CreateOptions) -> anyhow::Result<Self> {
+        if path.exists() {
+            anyhow::bail!("{} already exists", path.display());
+        }
+        queries::block::validate_block_size(options.block_size).context("invalid block size")?;
+        options.cipher.validate()?;
+        if key.is_none() && !options.cipher.is_default() {
+            anyhow::bail!("Cipher settings require a key");
+        }
+        let key = key.map(|key| options.cipher.sqlcipher_key(key)).transpose()?;
+        options.cipher.save(path)?;
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let mut ops = match Self::connect(path, key, options.cipher, flags) {
+            Ok(ops) => ops,
+            Err(e) => {
+                let _ = std::fs::remove_file(settings_path(path));
+                return Err(e);
+            }
+        };
+        ops.with_write_tx(|tx| {
+            queries::meta::set(tx, queries::meta::BLOCK_SIZE, options.block_size)?;
+            queries::meta::set_compression(tx, options.compression)?;
+            queries::meta::set(tx, queries::meta::MIN_SAVINGS, options.min_savings)?;
+            if let Some(label) = &options.label {
+                queries::meta::set(tx, queries::meta::LABEL, label)?;
+            }
+            // The root directory is ino 1, so that offline commands can read a database that was never mounted.
+            let mut root = FileAttrBuilder::new_directory().build();
+            queries::inode::create(tx, &mut root)?;
+            Ok(())
+        })?;
+        Ok(ops)
     }
 
     #[cfg(test)]
     pub fn open_in_memory() -> anyhow::Result<Self> {
         let mut db = rusqlite::Connection::open_in_memory().context("open")?;
         migrate_database(&mut db)?;
-        Ok(DatabaseOps { db })
+        Ok(DatabaseOps {
+            db,
+            cipher: CipherSettings::default(),
+        })
     }
 
     pub fn with_read_tx<T, F>(&mut self, scope: F) -> Result<T>
@@ -51,21 +175,76 @@ impl DatabaseOps {
         tx.commit()?;
         Ok(val)
     }
+
+    pub fn block_size(&mut self) -> Result<u64> {
+        self.with_read_tx(queries::meta::block_size)
+    }
+
+    /// Returns the default compression of the database, used when mounting without `--compress`.
+    pub fn compression(&mut self) -> Result<CompressionSpec> {
+        self.with_read_tx(queries::meta::compression)
+    }
+
+    fn check_features(&mut self) -> anyhow::Result<()> {
+        let unsupported = self.with_read_tx(queries::meta::unsupported_features)?;
+        if !unsupported.is_empty() {
+            anyhow::bail!(
+                "Database uses features unsupported by nightshift {}: {}",
+                env!("CARGO_PKG_VERSION"),
+                unsupported.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the problems found by `PRAGMA integrity_check`, empty when the database is sound.
+    pub fn integrity_check(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self.db.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        let mut messages = rows.collect::<rusqlite::Result<Vec<String>>>()?;
+        messages.retain(|message| message != "ok");
+        Ok(messages)
+    }
+
+    /// Returns the pages whose HMAC does not match, as reported by `PRAGMA cipher_integrity_check`. Plaintext
+    /// databases have no HMAC and never report any.
+    pub fn cipher_integrity_check(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self.db.prepare("PRAGMA cipher_integrity_check")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
     pub fn vacuum(&mut self) -> anyhow::Result<()> {
         self.db.execute("VACUUM;", params![])?;
         Ok(())
     }
 }
 
-fn set_cipher_key(db: &rusqlite::Connection, key: String) -> anyhow::Result<()> {
-    db.pragma_update(None, "key", key).context("pragma")?;
-    match db
-        .prepare("SELECT count(*) FROM sqlite_master")
+/// Reads the schema, which fails with `NotADatabase` when the pages cannot be decrypted.
+fn read_schema(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.prepare("SELECT count(*) FROM sqlite_master")
         .and_then(|mut stmt| stmt.query(params![]).map(|_| ()))
-    {
+}
+
+fn set_cipher_key(db: &rusqlite::Connection, key: String, cipher: &CipherSettings) -> anyhow::Result<()> {
+    db.pragma_update(None, "key", cipher.sqlcipher_key(key)?)
+        .context("pragma")?;
+    cipher.apply(db).context("cipher settings")?;
+    match read_schema(db) {
         Ok(_) => Ok(()),
         Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ffi::ErrorCode::NotADatabase => {
-            anyhow::bail!("Invalid key");
+            anyhow::bail!("Invalid key or cipher settings: unable to decrypt the database, or it is not encrypted");
+        }
+        Err(e) => anyhow::bail!("SQLite error: {e}"),
+    }
+}
+
+/// Fails early on an encrypted database opened without a key, instead of in the migrations.
+fn check_readable(db: &rusqlite::Connection) -> anyhow::Result<()> {
+    match read_schema(db) {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ffi::ErrorCode::NotADatabase => {
+            anyhow::bail!("Database is encrypted or not a SQLite database, a key is required");
         }
         Err(e) => anyhow::bail!("SQLite error: {e}"),
     }
@@ -78,7 +257,7 @@ pub(crate) fn migrate_database(db: &mut rusqlite::Connection) -> anyhow::Result<
 fn migrate_database_inner(db: &mut rusqlite::Connection) -> anyhow::Result<()> {
     db.execute_batch(include_str!("pragmas.sql"))?;
 
-    let tx = db.transaction()?;
+    let mut tx = db.transaction()?;
     let current_version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
     let mut last_version = current_version;
     for (&version, &migration) in &*MIGRATIONS {
@@ -104,6 +283,154 @@ fn migrate_database_inner(db: &mut rusqlite::Connection) -> anyhow::Result<()> {
         log::info!("Updating current_version to #{}", last_version);
         tx.pragma_update(None, "user_version", last_version)?;
     }
+    if current_version == 0 {
+        let now = TimeSpec::from(SystemTime::now());
+        queries::meta::set(
+            &mut tx,
+            queries::meta::CREATED_BY,
+            concat!("nightshift ", env!("CARGO_PKG_VERSION")),
+        )?;
+        queries::meta::set(&mut tx, queries::meta::CREATED_AT, now.secs)?;
+    }
     tx.commit()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::queries::{self, block::Compression};
+
+    use super::{CreateOptions, DatabaseOps};
+    use crate::cipher::{settings_path, CipherSettings, HmacAlgorithm};
+
+    #[test]
+    fn test_superblock() -> anyhow::Result<()> {
+        let mut db = DatabaseOps::open_in_memory()?;
+        let (uuid, created_by): (Option<String>, Option<String>) = db.with_read_tx(|tx| {
+            Ok((
+                queries::meta::get(tx, queries::meta::UUID)?,
+                queries::meta::get(tx, queries::meta::CREATED_BY)?,
+            ))
+        })?;
+        assert_eq!(uuid.expect("uuid").len(), 36);
+        assert_eq!(
+            created_by.as_deref(),
+            Some(concat!("nightshift ", env!("CARGO_PKG_VERSION")))
+        );
+        db.check_features()?;
+
+        assert_eq!(db.compression()?, Compression::LZ4.into());
+        let spec = "zstd:19".parse().unwrap();
+        db.with_write_tx(|tx| queries::meta::set_compression(tx, spec))?;
+        assert_eq!(db.compression()?, spec);
+        db.with_write_tx(|tx| queries::meta::set_compression(tx, Compression::Xz.into()))?;
+        assert!(db.with_read_tx(queries::meta::features)?.contains(&"xz".to_owned()));
+        db.check_features()?;
+
+        db.with_write_tx(|tx| queries::meta::set(tx, "feature.from_the_future", 1))?;
+        let err = db.check_features().unwrap_err();
+        assert!(err.to_string().contains("from_the_future"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_errors() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("db.sqlite");
+        DatabaseOps::create(&path, Some("secret".to_owned()), &CreateOptions::default())?;
+
+        let err = DatabaseOps::open(&path, None).err().expect("missing key");
+        assert!(err.to_string().contains("a key is required"));
+        let err = DatabaseOps::open(&path, Some("wrong".to_owned()))
+            .err()
+            .expect("wrong key");
+        assert!(err.to_string().starts_with("Invalid key"));
+        DatabaseOps::open(&path, Some("secret".to_owned()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_missing() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("missing.sqlite");
+        let err = DatabaseOps::open(&path, None).err().expect("missing database");
+        assert!(err.to_string().starts_with("No such database"));
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_or_create() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("db.sqlite");
+        let zstd = Compression::Zstd.into();
+        DatabaseOps::open_or_create(&path, None, Some(zstd))?;
+        assert_eq!(DatabaseOps::open(&path, None)?.compression()?, zstd);
+        // The compression of an existing database is only overridden for the mount.
+        let mut db = DatabaseOps::open_or_create(&path, None, Some(Compression::Xz.into()))?;
+        assert_eq!(db.compression()?, zstd);
+        drop(db);
+
+        let empty = tmp.path().join("empty.sqlite");
+        std::fs::write(&empty, b"")?;
+        DatabaseOps::open_or_create(&empty, None, Some(zstd))?;
+        assert_eq!(DatabaseOps::open(&empty, None)?.compression()?, zstd);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cipher_settings() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let key = Some("secret".to_owned());
+        let cipher = CipherSettings {
+            kdf_iter: Some(1000),
+            page_size: Some(8192),
+            hmac_algorithm: Some(HmacAlgorithm::Sha512),
+            ..Default::default()
+        };
+        let options = CreateOptions {
+            cipher,
+            ..Default::default()
+        };
+        let path = tmp.path().join("db.sqlite");
+        assert!(DatabaseOps::create(&path, None, &options).is_err());
+        DatabaseOps::create(&path, key.clone(), &options)?;
+        let db = DatabaseOps::open(&path, key.clone())?;
+        assert_eq!(db.cipher, cipher);
+        let page_size: String = db.db.pragma_query_value(None, "cipher_page_size", |row| row.get(0))?;
+        assert_eq!(page_size, "8192");
+        drop(db);
+        assert!(DatabaseOps::open_with(&path, key.clone(), CipherSettings::default()).is_err());
+
+        // A database of SQLCipher 3 opens with its defaults only.
+        let legacy = tmp.path().join("legacy.sqlite");
+        let db = rusqlite::Connection::open(&legacy)?;
+        db.pragma_update(None, "key", "secret")?;
+        db.pragma_update(None, "cipher_compatibility", 3)?;
+        db.execute_batch("CREATE TABLE legacy (id INTEGER)")?;
+        drop(db);
+        assert!(DatabaseOps::open(&legacy, key.clone()).is_err());
+        let compatibility = CipherSettings {
+            compatibility: Some(3),
+            ..Default::default()
+        };
+        DatabaseOps::open_with(&legacy, key.clone(), compatibility)?;
+
+        let raw = CipherSettings {
+            raw_key: true,
+            ..Default::default()
+        };
+        let options = CreateOptions {
+            cipher: raw,
+            ..Default::default()
+        };
+        let raw_path = tmp.path().join("raw.sqlite");
+        assert!(DatabaseOps::create(&raw_path, key.clone(), &options).is_err());
+        assert!(!settings_path(&raw_path).exists());
+        let raw_key = "2DD29CA851E7B56E4697B0E1F08507293D761A05CE4D1B628663F411A8086D99";
+        DatabaseOps::create(&raw_path, Some(raw_key.to_owned()), &options)?;
+        DatabaseOps::open(&raw_path, Some(format!("x'{raw_key}'")))?;
+        assert!(DatabaseOps::open_with(&raw_path, Some(raw_key.to_owned()), CipherSettings::default()).is_err());
+        Ok(())
+    }
+}
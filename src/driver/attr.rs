@@ -77,6 +77,20 @@ impl FileAttrBuilder {
         self
     }
 
+    pub fn with_times(
+        mut self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
+    ) -> FileAttrBuilder {
+        self.attr.atime = atime;
+        self.attr.mtime = mtime;
+        self.attr.ctime = ctime;
+        self.attr.crtime = crtime;
+        self
+    }
+
     pub fn build(self) -> FileAttr {
         self.attr
     }
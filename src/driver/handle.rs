@@ -1,11 +1,16 @@
 use std::cmp;
 
+use fuser::FileAttr;
+
 use crate::driver::OpenFlags;
 use crate::errors::Result;
 use crate::queries;
-use crate::queries::block::{Block, Compression};
+use crate::queries::block::{Block, Compressor};
 
 const BUFFER_SIZE: usize = 2 * 1024 * 1024;
+/// Files up to this size keep their contents in the inode row instead of the block table. Must not exceed
+/// `MIN_BLOCK_SIZE` since inline data is promoted to a single block.
+pub const INLINE_DATA_MAX: u64 = 4 * 1024;
 
 #[derive(Debug)]
 pub struct FileHandle {
@@ -17,18 +22,20 @@ pub struct FileHandle {
     write_offset: u64,
     /// Write data buffer used to optimize writes.
     pub buf: Vec<u8>,
-    compression: Compression,
+    block_size: u64,
+    compressor: Compressor,
 }
 
 impl FileHandle {
-    pub fn new(ino: u64, size: u64, flags: OpenFlags, compression: Compression) -> Self {
+    pub fn new(ino: u64, size: u64, flags: OpenFlags, block_size: u64, compressor: Compressor) -> Self {
         FileHandle {
             ino,
             size,
             flags,
             write_offset: 0,
-            buf: Vec::with_capacity(BUFFER_SIZE),
-            compression,
+            buf: Vec::with_capacity(cmp::max(BUFFER_SIZE, block_size as usize)),
+            block_size,
+            compressor,
         }
     }
 
@@ -70,12 +77,74 @@ impl FileHandle {
         );
 
         let mut attr = queries::inode::lookup(tx, self.ino)?;
+
+        match self.load_inline(tx, &attr)? {
+            Some(block) if self.write_offset() <= INLINE_DATA_MAX => self.flush_inline(tx, &mut attr, block)?,
+            Some(block) => {
+                self.promote_inline(tx, block)?;
+                self.flush_blocks(tx, &mut attr)?;
+            }
+            None => self.flush_blocks(tx, &mut attr)?,
+        }
+
+        attr.blocks = attr.size.div_ceil(attr.blksize as u64);
+        queries::inode::set_attr(tx, self.ino, "size", attr.size)?;
+        queries::inode::set_attr(tx, self.ino, "blocks", attr.blocks)?;
+
+        self.buf.clear();
+        self.size = attr.size;
+
+        Ok(())
+    }
+
+    /// Returns the inline data of the file. Empty files always start out inline.
+    fn load_inline(&self, tx: &mut rusqlite::Transaction, attr: &FileAttr) -> Result<Option<Block>> {
+        if let Some(block) = queries::inode::get_inline(tx, self.ino, self.block_size)? {
+            return Ok(Some(block));
+        }
+        if attr.size == 0 {
+            // Truncating a file to 0 leaves an empty block 0 behind.
+            queries::block::remove_blocks_from(tx, self.ino, 0)?;
+            return Ok(Some(Block::empty(self.ino, 0, self.block_size)));
+        }
+        Ok(None)
+    }
+
+    fn flush_inline(&mut self, tx: &mut rusqlite::Transaction, attr: &mut FileAttr, mut block: Block) -> Result<()> {
+        let (written, diff) = block.write_at(self.write_offset, &self.buf);
+        log::debug!(
+            "Update inline data at offset={}, written={}, diff={}",
+            self.write_offset,
+            written,
+            diff
+        );
+        queries::inode::set_inline(tx, &block, &mut self.compressor)?;
+        attr.size = (attr.size as i64 + diff) as u64;
+        self.write_offset += written;
+        Ok(())
+    }
+
+    /// Moves the inline data of the file to the block table once it outgrows `INLINE_DATA_MAX`.
+    fn promote_inline(&mut self, tx: &mut rusqlite::Transaction, block: Block) -> Result<()> {
+        log::debug!(
+            "Promote inline data of ino {} to blocks, len={}",
+            self.ino,
+            block.data.len()
+        );
+        queries::inode::clear_inline(tx, self.ino)?;
+        if !block.data.is_empty() {
+            queries::block::create(tx, self.ino, 0, &block.data, self.block_size, &mut self.compressor)?;
+        }
+        Ok(())
+    }
+
+    fn flush_blocks(&mut self, tx: &mut rusqlite::Transaction, attr: &mut FileAttr) -> Result<()> {
         let mut new_offset = self.write_offset;
         let mut data = &self.buf[..];
         let mut modified_blocks = Vec::new();
 
         // Update blocks if the start offset overrides blocks.
-        queries::block::iter_blocks_from(tx, self.ino, new_offset, |mut block| {
+        queries::block::iter_blocks_from(tx, self.ino, new_offset, self.block_size, |mut block| {
             let (written, diff) = block.write_at(new_offset, data);
             log::debug!(
                 "Update block {} at offset={}, written={}, diff={}",
@@ -94,15 +163,16 @@ impl FileHandle {
         })?;
 
         for block in modified_blocks {
-            queries::block::update(tx, &block, self.compression)?;
+            queries::block::update(tx, &block, &mut self.compressor)?;
         }
 
         // Write the rest of the data in a new block.
         while !data.is_empty() {
-            let written = queries::block::create(tx, self.ino, new_offset, data, self.compression)?;
+            let written =
+                queries::block::create(tx, self.ino, new_offset, data, self.block_size, &mut self.compressor)?;
             log::debug!(
                 "Create block {} at offset={}, written={}, diff={}",
-                Block::offset_to_bno(new_offset),
+                Block::offset_to_bno(new_offset, self.block_size),
                 new_offset,
                 written,
                 written
@@ -112,14 +182,7 @@ impl FileHandle {
             attr.size += written;
         }
 
-        attr.blocks = attr.size.div_ceil(attr.blksize as u64);
-        queries::inode::set_attr(tx, self.ino, "size", attr.size)?;
-        queries::inode::set_attr(tx, self.ino, "blocks", attr.blocks)?;
-
-        self.buf.clear();
         self.write_offset = new_offset;
-        self.size = attr.size;
-
         Ok(())
     }
 }
@@ -127,9 +190,10 @@ impl FileHandle {
 #[cfg(test)]
 mod tests {
     use crate::driver::attr::FileAttrBuilder;
+    use crate::driver::handle::INLINE_DATA_MAX;
     use crate::driver::{FileHandle, OpenFlags};
     use crate::queries;
-    use crate::queries::block::{Compression, BLOCK_SIZE};
+    use crate::queries::block::{Compression, Compressor, DEFAULT_BLOCK_SIZE};
     use test_log::test;
 
     #[test]
@@ -140,7 +204,8 @@ mod tests {
             flags: OpenFlags::from(0),
             write_offset: 0,
             buf: Vec::with_capacity(37),
-            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compressor: Compressor::new(Compression::None.into()),
         };
         assert_eq!(fh.buffer_remaining(), 37);
     }
@@ -153,7 +218,8 @@ mod tests {
             flags: OpenFlags::from(0),
             write_offset: 0,
             buf: vec![0; 37],
-            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compressor: Compressor::new(Compression::None.into()),
         };
         assert!(fh.buffer_full());
         fh.buf.reserve(10);
@@ -168,7 +234,8 @@ mod tests {
             flags: OpenFlags::from(0),
             write_offset: 0,
             buf: Vec::with_capacity(1000),
-            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compressor: Compressor::new(Compression::None.into()),
         };
         fh.seek_to(500);
         assert_eq!(fh.write_offset(), 500);
@@ -183,7 +250,8 @@ mod tests {
             flags: OpenFlags::from(0),
             write_offset: 0,
             buf: vec![0; 37],
-            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compressor: Compressor::new(Compression::None.into()),
         };
         fh.seek_to(0);
     }
@@ -196,7 +264,8 @@ mod tests {
             flags: OpenFlags::from(0),
             write_offset: 1000,
             buf: Vec::with_capacity(64),
-            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compressor: Compressor::new(Compression::None.into()),
         };
         assert_eq!(5, fh.consume_input(&[5; 5]));
         assert_eq!(59, fh.consume_input(&[5; 100]));
@@ -211,45 +280,93 @@ mod tests {
 
         let mut attr = FileAttrBuilder::new_node(crate::types::FileType::RegularFile).build();
         queries::inode::create(&mut tx, &mut attr)?;
-        let mut fh = FileHandle::new(attr.ino, attr.size, OpenFlags::from(0), Compression::None);
+        let mut fh = FileHandle::new(
+            attr.ino,
+            attr.size,
+            OpenFlags::from(0),
+            DEFAULT_BLOCK_SIZE,
+            Compressor::new(Compression::None.into()),
+        );
 
         //
         // Simple consecutive write...
         //
-        fh.consume_input(&[1u8; (BLOCK_SIZE + 100) as usize]);
+        fh.consume_input(&[1u8; (DEFAULT_BLOCK_SIZE + 100) as usize]);
         fh.flush(&mut tx)?;
 
         let mut total_size = 0;
         let mut block_num = 0;
 
-        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, |block| {
+        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, DEFAULT_BLOCK_SIZE, |block| {
             block_num += 1;
             total_size += block.data.len();
             Ok(true)
         })?;
 
-        assert_eq!(total_size, (BLOCK_SIZE + 100) as usize);
+        assert_eq!(total_size, (DEFAULT_BLOCK_SIZE + 100) as usize);
         assert_eq!(block_num, 2);
 
         //
         // Seek and overwrite
         //
-        fh.seek_to(BLOCK_SIZE / 2);
-        fh.consume_input(&[2u8; (BLOCK_SIZE * 2) as usize]);
+        fh.seek_to(DEFAULT_BLOCK_SIZE / 2);
+        fh.consume_input(&[2u8; (DEFAULT_BLOCK_SIZE * 2) as usize]);
         fh.flush(&mut tx)?;
 
         let mut total_size = 0;
         let mut block_num = 0;
 
-        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, |block| {
+        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, DEFAULT_BLOCK_SIZE, |block| {
             block_num += 1;
             total_size += block.data.len();
             Ok(true)
         })?;
 
-        assert_eq!(total_size, (BLOCK_SIZE * 2 + (BLOCK_SIZE / 2)) as usize);
+        assert_eq!(total_size, (DEFAULT_BLOCK_SIZE * 2 + (DEFAULT_BLOCK_SIZE / 2)) as usize);
         assert_eq!(block_num, 3);
 
         Ok(())
     }
+
+    #[test]
+    fn test_file_handle_flush_inline() -> anyhow::Result<()> {
+        let mut cx = rusqlite::Connection::open_in_memory()?;
+        crate::database::migrate_database(&mut cx)?;
+        let mut tx = cx.transaction()?;
+
+        let mut attr = FileAttrBuilder::new_node(crate::types::FileType::RegularFile).build();
+        queries::inode::create(&mut tx, &mut attr)?;
+        let mut fh = FileHandle::new(
+            attr.ino,
+            attr.size,
+            OpenFlags::from(0),
+            DEFAULT_BLOCK_SIZE,
+            Compressor::new(Compression::LZ4.into()),
+        );
+
+        //
+        // Small files are stored in the inode row
+        //
+        fh.consume_input(&[1u8; 100]);
+        fh.flush(&mut tx)?;
+
+        let inline = queries::inode::get_inline(&mut tx, attr.ino, DEFAULT_BLOCK_SIZE)?.expect("inline data");
+        assert_eq!(inline.data, vec![1u8; 100]);
+        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, DEFAULT_BLOCK_SIZE, |_| panic!("unexpected block"))?;
+        assert_eq!(queries::inode::lookup(&mut tx, attr.ino)?.size, 100);
+
+        //
+        // Growing past the threshold moves the data to blocks
+        //
+        fh.consume_input(&[2u8; INLINE_DATA_MAX as usize]);
+        fh.flush(&mut tx)?;
+
+        assert!(queries::inode::get_inline(&mut tx, attr.ino, DEFAULT_BLOCK_SIZE)?.is_none());
+        let block = queries::block::get_block(&mut tx, attr.ino, 0, DEFAULT_BLOCK_SIZE)?;
+        assert_eq!(block.data.len(), 100 + INLINE_DATA_MAX as usize);
+        assert_eq!(&block.data[..100], &[1u8; 100]);
+        assert_eq!(queries::inode::lookup(&mut tx, attr.ino)?.size, 100 + INLINE_DATA_MAX);
+
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 #![allow(clippy::too_many_arguments)]
 
-mod attr;
+pub mod attr;
 mod flags;
 mod handle;
 mod request_info;
@@ -11,6 +11,7 @@ use std::{
     fs,
     os::unix::fs::MetadataExt,
     path::Path,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
@@ -18,7 +19,12 @@ use attr::FileAttrBuilder;
 use fuser::FileAttr;
 use slab::Slab;
 
-use crate::queries::{self, block::Compression, dir_entry::ListDirEntry};
+use crate::queries::{
+    self,
+    block::{Compression, CompressionSpec, Compressor},
+    dictionary::Dictionary,
+    dir_entry::ListDirEntry,
+};
 use crate::types::FileType;
 use crate::{database::DatabaseOps, time::TimeSpec};
 use crate::{
@@ -31,20 +37,37 @@ pub use request_info::RequestInfo;
 
 const DURATION: Duration = Duration::from_secs(0);
 
+/// Extended attribute holding the compression policy of a file or directory. New files and directories inherit the
+/// policy of their parent.
+pub(crate) const XATTR_COMPRESSION: &str = "user.nightshift.compression";
+
 pub struct FuseDriver {
     pub db: DatabaseOps,
-    compression: Compression,
+    block_size: u64,
+    compression: CompressionSpec,
+    min_savings: u8,
+    dictionary: Option<Arc<Dictionary>>,
     handles: Slab<FileHandle>,
     mount_uid: u32,
     mount_gid: u32,
 }
 
 impl FuseDriver {
-    pub fn new(db: DatabaseOps, compression: Compression, mount_path: &Path) -> anyhow::Result<Self> {
+    pub fn new(mut db: DatabaseOps, compression: CompressionSpec, mount_path: &Path) -> anyhow::Result<Self> {
         let md = fs::metadata(mount_path)?;
+        let block_size = db.block_size()?;
+        let min_savings = db.with_read_tx(queries::meta::min_savings)?;
+        let dictionary = db.with_read_tx(queries::dictionary::latest)?.map(Arc::new);
+        if compression.compression == Compression::ZstdDict && dictionary.is_none() {
+            anyhow::bail!("Database has no zstd dictionary, run train-dict first");
+        }
+        db.with_write_tx(|tx| queries::meta::enable_compression(tx, compression))?;
         Ok(Self {
             db,
+            block_size,
             compression,
+            min_savings,
+            dictionary,
             handles: Slab::new(),
             mount_uid: md.uid(),
             mount_gid: md.gid(),
@@ -52,16 +75,34 @@ impl FuseDriver {
     }
 
     #[cfg(test)]
-    pub fn new_no_io(db: DatabaseOps, compression: Compression) -> Self {
+    pub fn new_no_io(mut db: DatabaseOps, compression: CompressionSpec) -> Self {
+        let block_size = db.block_size().expect("block size");
+        let min_savings = db.with_read_tx(queries::meta::min_savings).expect("min savings");
+        let dictionary = db
+            .with_read_tx(queries::dictionary::latest)
+            .expect("dictionary")
+            .map(Arc::new);
+        db.with_write_tx(|tx| queries::meta::enable_compression(tx, compression))
+            .expect("enable compression");
         Self {
             db,
+            block_size,
             compression,
+            min_savings,
+            dictionary,
             handles: Slab::new(),
             mount_uid: 0,
             mount_gid: 0,
         }
     }
 
+    /// Returns a compressor for a file, using its compression policy if it has one.
+    fn compressor(&self, policy: Option<CompressionSpec>) -> Compressor {
+        Compressor::new(policy.unwrap_or(self.compression))
+            .with_min_savings(self.min_savings)
+            .with_dictionary(self.dictionary.clone())
+    }
+
     fn ensure_root_exists(&mut self) -> Result<()> {
         self.db.with_write_tx(|tx| {
             match queries::inode::lookup(tx, 1) {
@@ -118,6 +159,11 @@ impl FuseDriver {
         _bkuptime: Option<TimeSpec>,
         flags: Option<u32>,
     ) -> Result<FileAttr> {
+        let policy = match size {
+            Some(_) => self.db.with_read_tx(|tx| queries::inode::get_compression(tx, ino))?,
+            None => None,
+        };
+        let mut compressor = self.compressor(policy);
         self.db.with_write_tx(|tx| {
             if let Some(mode) = mode {
                 queries::inode::set_attr(tx, ino, "perm", mode)?;
@@ -129,15 +175,20 @@ impl FuseDriver {
                 queries::inode::set_attr(tx, ino, "gid", gid)?;
             }
             if let Some(size) = size {
-                let bno = Block::offset_to_bno(size);
-                queries::block::remove_blocks_from(tx, ino, bno + 1)?;
-                match queries::block::get_block(tx, ino, bno) {
-                    Ok(mut block) => {
-                        block.truncate(size);
-                        queries::block::update(tx, &block, self.compression)?;
+                if let Some(mut block) = queries::inode::get_inline(tx, ino, self.block_size)? {
+                    block.truncate(size);
+                    queries::inode::set_inline(tx, &block, &mut compressor)?;
+                } else {
+                    let bno = Block::offset_to_bno(size, self.block_size);
+                    queries::block::remove_blocks_from(tx, ino, bno + 1)?;
+                    match queries::block::get_block(tx, ino, bno, self.block_size) {
+                        Ok(mut block) => {
+                            block.truncate(size);
+                            queries::block::update(tx, &block, &mut compressor)?;
+                        }
+                        Err(Error::NotFound) => {}
+                        Err(e) => return Err(e),
                     }
-                    Err(Error::NotFound) => {}
-                    Err(e) => return Err(e),
                 }
                 queries::inode::set_attr(tx, ino, "size", size)?;
             }
@@ -185,6 +236,7 @@ impl FuseDriver {
 
         self.db.with_write_tx(|tx| {
             queries::inode::create(tx, &mut attr)?;
+            queries::inode::inherit_compression(tx, parent, attr.ino)?;
             queries::dir_entry::create(tx, parent, name, attr.ino)?;
             Ok(attr)
         })
@@ -226,6 +278,7 @@ impl FuseDriver {
 
         self.db.with_write_tx(|tx| {
             queries::inode::create(tx, &mut attr)?;
+            queries::inode::inherit_compression(tx, parent, attr.ino)?;
             queries::dir_entry::create(tx, parent, name, attr.ino)?;
             Ok(attr)
         })
@@ -254,10 +307,16 @@ impl FuseDriver {
     }
 
     fn open_impl(&mut self, _req: RequestInfo, ino: u64, flags: OpenFlags) -> Result<(u64, u32)> {
-        let attr = self.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?;
+        let (attr, policy) = self.db.with_read_tx(|tx| {
+            Ok((
+                queries::inode::lookup(tx, ino)?,
+                queries::inode::get_compression(tx, ino)?,
+            ))
+        })?;
+        let compressor = self.compressor(policy);
         let fh = self
             .handles
-            .insert(FileHandle::new(ino, attr.size, flags, self.compression));
+            .insert(FileHandle::new(ino, attr.size, flags, self.block_size, compressor));
         let fh = u64::try_from(fh).map_err(|_| Error::Overflow)?;
         Ok((fh, flags.bits as u32))
     }
@@ -298,14 +357,22 @@ impl FuseDriver {
         self.db.with_read_tx(|tx| {
             let attr = queries::inode::lookup(tx, ino)?;
             let offset = offset as u64;
-            let remaining = attr.size - offset;
+            let remaining = attr.size.saturating_sub(offset);
             let cap = cmp::min(size as u64, remaining) as usize;
             let mut buf = Vec::with_capacity(cap);
 
-            queries::block::iter_blocks_from(tx, ino, offset, |block| {
-                block.copy_into(&mut buf, offset);
-                Ok(buf.len() < buf.capacity())
-            })?;
+            if let Some(block) = queries::inode::get_inline(tx, ino, self.block_size)? {
+                if offset < block.data.len() as u64 {
+                    block.copy_into(&mut buf, offset);
+                }
+            } else {
+                queries::block::iter_blocks_from(tx, ino, offset, self.block_size, |block| {
+                    block.copy_into(&mut buf, offset);
+                    Ok(buf.len() < buf.capacity())
+                })?;
+            }
+            // A truncate that extends the file does not store the zeros past the data.
+            buf.resize(cap, 0);
             assert!(buf.len() <= size as usize);
             Ok(buf)
         })
@@ -368,6 +435,71 @@ impl FuseDriver {
         self.db
             .with_write_tx(|tx| queries::dir_entry::rename(tx, parent, name, newparent, newname))
     }
+
+    fn getxattr_impl(&mut self, _req: RequestInfo, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        if name != XATTR_COMPRESSION {
+            return Err(Error::NoAttribute);
+        }
+        let policy = self.db.with_read_tx(|tx| queries::inode::get_compression(tx, ino))?;
+        policy
+            .map(|spec| spec.to_string().into_bytes())
+            .ok_or(Error::NoAttribute)
+    }
+
+    fn setxattr_impl(&mut self, _req: RequestInfo, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+        if name != XATTR_COMPRESSION {
+            return Err(Error::Unsupported);
+        }
+        let value = std::str::from_utf8(value).map_err(|_| Error::InvalidArgument)?;
+        let spec: CompressionSpec = value
+            .trim_end_matches(['\0', '\n'])
+            .parse()
+            .map_err(|_| Error::InvalidArgument)?;
+        self.db.with_write_tx(|tx| {
+            let exists = queries::inode::get_compression(tx, ino)?.is_some();
+            if exists && flags & libc::XATTR_CREATE != 0 {
+                return Err(Error::AlreadyExists);
+            }
+            if !exists && flags & libc::XATTR_REPLACE != 0 {
+                return Err(Error::NoAttribute);
+            }
+            queries::meta::enable_compression(tx, spec)?;
+            queries::inode::set_compression(tx, ino, Some(spec))
+        })
+    }
+
+    fn listxattr_impl(&mut self, _req: RequestInfo, ino: u64) -> Result<Vec<u8>> {
+        let policy = self.db.with_read_tx(|tx| queries::inode::get_compression(tx, ino))?;
+        let mut names = Vec::new();
+        if policy.is_some() {
+            names.extend_from_slice(XATTR_COMPRESSION.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    fn removexattr_impl(&mut self, _req: RequestInfo, ino: u64, name: &OsStr) -> Result<()> {
+        if name != XATTR_COMPRESSION {
+            return Err(Error::NoAttribute);
+        }
+        self.db.with_write_tx(|tx| {
+            if queries::inode::get_compression(tx, ino)?.is_none() {
+                return Err(Error::NoAttribute);
+            }
+            queries::inode::set_compression(tx, ino, None)
+        })
+    }
+}
+
+/// Replies with the size of `data` when the kernel asks for it, or with `data` when it fits in `size`.
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
 }
 
 impl fuser::Filesystem for FuseDriver {
@@ -376,7 +508,10 @@ impl fuser::Filesystem for FuseDriver {
         _req: &fuser::Request<'_>,
         config: &mut fuser::KernelConfig,
     ) -> std::result::Result<(), libc::c_int> {
-        config.set_max_write(128 * 1024).expect("unable to set max_write");
+        let max_write = u32::try_from(self.block_size).expect("block size overflow");
+        if let Err(nearest) = config.set_max_write(max_write) {
+            config.set_max_write(nearest).expect("unable to set max_write");
+        }
         match self.ensure_root_exists() {
             Ok(()) => Ok(()),
             Err(e) => {
@@ -668,6 +803,59 @@ impl fuser::Filesystem for FuseDriver {
             Err(e) => reply.error(e.errno()),
         }
     }
+
+    fn getxattr(&mut self, req: &fuser::Request<'_>, ino: u64, name: &OsStr, size: u32, reply: fuser::ReplyXattr) {
+        log::trace!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+        let res = self.getxattr_impl(req.into(), ino, name);
+        log::trace!("getxattr: {:?}", res);
+
+        match res {
+            Ok(data) => reply_xattr(reply, size, &data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::trace!("setxattr(ino={}, name={:?}, flags={:#x})", ino, name, flags);
+        let res = self.setxattr_impl(req.into(), ino, name, value, flags);
+        log::trace!("setxattr: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn listxattr(&mut self, req: &fuser::Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        log::trace!("listxattr(ino={}, size={})", ino, size);
+        let res = self.listxattr_impl(req.into(), ino);
+        log::trace!("listxattr: {:?}", res);
+
+        match res {
+            Ok(data) => reply_xattr(reply, size, &data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn removexattr(&mut self, req: &fuser::Request<'_>, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        log::trace!("removexattr(ino={}, name={:?})", ino, name);
+        let res = self.removexattr_impl(req.into(), ino, name);
+        log::trace!("removexattr: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -678,7 +866,10 @@ mod tests {
     use crate::{
         database::DatabaseOps,
         errors::Error,
-        queries::{self, block::Compression},
+        queries::{
+            self,
+            block::{Compression, Compressor, DEFAULT_BLOCK_SIZE},
+        },
         types::FileType,
     };
     use rand::{Rng, RngCore};
@@ -688,7 +879,7 @@ mod tests {
     fn count_blocks(driver: &mut FuseDriver, ino: u64) -> anyhow::Result<usize> {
         let mut block_count = 0;
         driver.db.with_read_tx(|tx| {
-            queries::block::iter_blocks_from(tx, ino, 0, |_| {
+            queries::block::iter_blocks_from(tx, ino, 0, driver.block_size, |_| {
                 block_count += 1;
                 Ok(true)
             })
@@ -699,7 +890,7 @@ mod tests {
     #[test]
     fn test_lookup() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, queries::block::Compression::None);
+        let mut driver = FuseDriver::new_no_io(db, queries::block::Compression::None.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
         let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
@@ -728,7 +919,7 @@ mod tests {
     #[test]
     fn test_mknod() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, queries::block::Compression::LZ4);
+        let mut driver = FuseDriver::new_no_io(db, queries::block::Compression::LZ4.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
 
@@ -763,7 +954,7 @@ mod tests {
     #[test]
     fn test_link_unlink() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, Compression::Zstd);
+        let mut driver = FuseDriver::new_no_io(db, Compression::Zstd.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
         let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
@@ -775,7 +966,14 @@ mod tests {
             queries::inode::create(tx, &mut root_dir)?;
             queries::inode::create(tx, &mut node)?;
             queries::dir_entry::create(tx, root_dir.ino, OsStr::new("foo.txt"), node.ino)?;
-            queries::block::create(tx, node.ino, 0, b"hello world!", queries::block::Compression::Zstd)?;
+            queries::block::create(
+                tx,
+                node.ino,
+                0,
+                b"hello world!",
+                DEFAULT_BLOCK_SIZE,
+                &mut Compressor::new(Compression::Zstd.into()),
+            )?;
             Ok(())
         })?;
 
@@ -820,7 +1018,7 @@ mod tests {
     #[test]
     fn test_mkdir() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        let mut driver = FuseDriver::new_no_io(db, Compression::None.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
 
@@ -848,7 +1046,7 @@ mod tests {
     #[test]
     fn test_rmdir() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        let mut driver = FuseDriver::new_no_io(db, Compression::None.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
         let mut dir1 = FileAttrBuilder::new_directory().build();
@@ -879,7 +1077,7 @@ mod tests {
     #[test]
     fn test_read_write_cycle() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        let mut driver = FuseDriver::new_no_io(db, Compression::None.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
         let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
@@ -905,10 +1103,70 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_truncate_inline() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4.into());
+
+        let attr = driver.mknod_impl(RequestInfo::default(), 1, OsStr::new("foo"), libc::S_IFREG, 0, 0)?;
+        let (fh, _) = driver.open_impl(RequestInfo::default(), attr.ino, OpenFlags::from(libc::O_RDWR))?;
+        driver.write_impl(RequestInfo::default(), attr.ino, fh, 0, &[1u8; 400], 0, 0, None)?;
+        driver.flush_impl(RequestInfo::default(), attr.ino, fh, 0)?;
+
+        let attr = driver.setattr_impl(
+            RequestInfo::default(),
+            attr.ino,
+            None,
+            None,
+            None,
+            Some(100),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(attr.size, 100);
+
+        let data = driver.read_impl(RequestInfo::default(), attr.ino, fh, 0, 400, 0, None)?;
+        assert_eq!(data, vec![1u8; 100]);
+        assert_eq!(count_blocks(&mut driver, attr.ino)?, 0);
+
+        // Extending the file reads zeros past the inline data.
+        let attr = driver.setattr_impl(
+            RequestInfo::default(),
+            attr.ino,
+            None,
+            None,
+            None,
+            Some(300),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(attr.size, 300);
+        let data = driver.read_impl(RequestInfo::default(), attr.ino, fh, 0, 400, 0, None)?;
+        assert_eq!(data[..100], [1u8; 100]);
+        assert_eq!(data[100..], [0u8; 200]);
+        let data = driver.read_impl(RequestInfo::default(), attr.ino, fh, 200, 400, 0, None)?;
+        assert_eq!(data, vec![0u8; 100]);
+        let data = driver.read_impl(RequestInfo::default(), attr.ino, fh, 500, 400, 0, None)?;
+        assert!(data.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_rename() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
-        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        let mut driver = FuseDriver::new_no_io(db, Compression::None.into());
 
         let mut root_dir = FileAttrBuilder::new_directory().build();
         let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
@@ -946,11 +1204,113 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_corrupted_block() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4.into());
+        driver.ensure_root_exists()?;
+        let req = RequestInfo::default();
+
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 7) as u8).collect();
+        let mut inos = Vec::new();
+        for name in ["bad", "good"] {
+            let attr = driver.mknod_impl(req, 1, OsStr::new(name), libc::S_IFREG | 0o644, 0, 0)?;
+            let (fh, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_WRONLY))?;
+            driver.write_impl(req, attr.ino, fh, 0, &data, 0, 0, None)?;
+            driver.release_impl(req, attr.ino, fh, 0, None, false)?;
+            inos.push(attr.ino);
+        }
+        driver.db.with_write_tx(|tx| {
+            tx.execute(
+                "UPDATE block SET data = x'ffffffff' WHERE ino = ? AND bno = 1",
+                [inos[0]],
+            )?;
+            Ok(())
+        })?;
+
+        let (fh, _) = driver.open_impl(req, inos[0], OpenFlags::from(libc::O_RDONLY))?;
+        assert_eq!(driver.read_impl(req, inos[0], fh, 0, 4096, 0, None)?, data[..4096]);
+        let res = driver.read_impl(req, inos[0], fh, DEFAULT_BLOCK_SIZE as i64, 4096, 0, None);
+        assert_eq!(res, Err(Error::Corrupted));
+        driver.release_impl(req, inos[0], fh, 0, None, false)?;
+
+        let (fh, _) = driver.open_impl(req, inos[1], OpenFlags::from(libc::O_RDONLY))?;
+        let read = driver.read_impl(req, inos[1], fh, DEFAULT_BLOCK_SIZE as i64, 4096, 0, None)?;
+        assert_eq!(read, data[DEFAULT_BLOCK_SIZE as usize..][..4096]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_policy() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4.into());
+        driver.ensure_root_exists()?;
+        let req = RequestInfo::default();
+        let name = OsStr::new(super::XATTR_COMPRESSION);
+
+        let photos = driver.mkdir_impl(req, 1, OsStr::new("photos"), 0o755, 0)?;
+        assert_eq!(driver.getxattr_impl(req, photos.ino, name), Err(Error::NoAttribute));
+        assert_eq!(
+            driver.setxattr_impl(req, photos.ino, name, b"gzip", 0),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(
+            driver.setxattr_impl(req, photos.ino, name, b"none", libc::XATTR_REPLACE),
+            Err(Error::NoAttribute)
+        );
+        driver.setxattr_impl(req, photos.ino, name, b"none\n", libc::XATTR_CREATE)?;
+        assert_eq!(driver.getxattr_impl(req, photos.ino, name)?, b"none");
+        assert_eq!(
+            driver.listxattr_impl(req, photos.ino)?,
+            b"user.nightshift.compression\0"
+        );
+
+        let album = driver.mkdir_impl(req, photos.ino, OsStr::new("album"), 0o755, 0)?;
+        assert_eq!(driver.getxattr_impl(req, album.ino, name)?, b"none");
+        let resolved = driver
+            .db
+            .with_read_tx(|tx| queries::dir_entry::resolve(tx, std::path::Path::new("/photos/album")))?;
+        assert_eq!(resolved, album.ino);
+
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 7) as u8).collect();
+        let write_file = |driver: &mut FuseDriver, parent: u64| -> anyhow::Result<u64> {
+            let attr = driver.mknod_impl(req, parent, OsStr::new("img.jpg"), libc::S_IFREG | 0o644, 0, 0)?;
+            let (fh, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_WRONLY))?;
+            driver.write_impl(req, attr.ino, fh, 0, &data, 0, 0, None)?;
+            driver.release_impl(req, attr.ino, fh, 0, None, false)?;
+            Ok(attr.ino)
+        };
+        let stored = write_file(&mut driver, album.ino)?;
+        let compressed = write_file(&mut driver, 1)?;
+
+        let compressions = |driver: &mut FuseDriver, ino: u64| -> anyhow::Result<Vec<u8>> {
+            Ok(driver.db.with_read_tx(|tx| {
+                let mut stmt = tx.prepare("SELECT compression FROM block WHERE ino = ? ORDER BY bno")?;
+                let rows = stmt.query_map([ino], |row| row.get(0))?;
+                Ok(rows.collect::<rusqlite::Result<Vec<u8>>>()?)
+            })?)
+        };
+        assert_eq!(compressions(&mut driver, stored)?, vec![Compression::None as u8; 2]);
+        assert_eq!(compressions(&mut driver, compressed)?, vec![Compression::LZ4 as u8; 2]);
+
+        driver.removexattr_impl(req, album.ino, name)?;
+        assert_eq!(driver.listxattr_impl(req, album.ino)?, b"");
+        assert_eq!(driver.removexattr_impl(req, album.ino, name), Err(Error::NoAttribute));
+
+        Ok(())
+    }
+
     #[test]
     fn test_for_corruption() -> anyhow::Result<()> {
         let mut rng = rand::thread_rng();
 
-        for compression in [Compression::None, Compression::LZ4, Compression::Zstd] {
+        for compression in [
+            Compression::None.into(),
+            Compression::LZ4.into(),
+            Compression::Zstd.into(),
+            "zstd:-5:long".parse().unwrap(),
+        ] {
             dbg!(compression);
 
             let db = DatabaseOps::open_in_memory()?;
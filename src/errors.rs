@@ -1,4 +1,4 @@
-use rusqlite::types::FromSqlError;
+use rusqlite::{types::FromSqlError, ErrorCode};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -10,6 +10,15 @@ pub enum Error {
     Overflow,
     Other(String),
     InvalidCompression,
+    AlreadyExists,
+    NoAttribute,
+    Unsupported,
+    Corrupted,
+    NoSpace,
+    Busy,
+    Locked,
+    ReadOnly,
+    Io,
 }
 
 impl Error {
@@ -20,6 +29,15 @@ impl Error {
             Error::InvalidArgument => libc::EINVAL,
             Error::Overflow => libc::EOVERFLOW,
             Error::InvalidCompression => libc::EINVAL,
+            Error::AlreadyExists => libc::EEXIST,
+            Error::NoAttribute => libc::ENODATA,
+            Error::Unsupported => libc::ENOTSUP,
+            Error::Corrupted => libc::EIO,
+            Error::NoSpace => libc::ENOSPC,
+            Error::Busy => libc::EAGAIN,
+            Error::Locked => libc::EBUSY,
+            Error::ReadOnly => libc::EROFS,
+            Error::Io => libc::EIO,
             Error::Other(_) => libc::ENOTSUP, // Need better code
         }
     }
@@ -29,6 +47,20 @@ impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Self {
         match err {
             rusqlite::Error::QueryReturnedNoRows => Error::NotFound,
+            rusqlite::Error::SqliteFailure(ref e, _) => {
+                let error = match e.code {
+                    ErrorCode::DiskFull => Error::NoSpace,
+                    ErrorCode::DatabaseBusy => Error::Busy,
+                    ErrorCode::DatabaseLocked => Error::Locked,
+                    ErrorCode::ReadOnly => Error::ReadOnly,
+                    ErrorCode::SystemIoFailure => Error::Io,
+                    ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => Error::Corrupted,
+                    ErrorCode::ConstraintViolation => Error::AlreadyExists,
+                    _ => return Error::Other(err.to_string()),
+                };
+                log::debug!("SQLite error: {}", err);
+                error
+            }
             _ => Error::Other(err.to_string()),
         }
     }
@@ -48,9 +80,65 @@ impl std::fmt::Display for Error {
             Error::InvalidArgument => write!(f, "Invalid Argument"),
             Error::Overflow => write!(f, "Overflow"),
             Error::InvalidCompression => write!(f, "Invalid Compression Scheme"),
+            Error::AlreadyExists => write!(f, "Already Exists"),
+            Error::NoAttribute => write!(f, "No Such Attribute"),
+            Error::Unsupported => write!(f, "Unsupported"),
+            Error::Corrupted => write!(f, "Corrupted Data"),
+            Error::NoSpace => write!(f, "No Space Left"),
+            Error::Busy => write!(f, "Busy"),
+            Error::Locked => write!(f, "Locked"),
+            Error::ReadOnly => write!(f, "Read Only"),
+            Error::Io => write!(f, "I/O Error"),
             Error::Other(msg) => write!(f, "Other: {}", msg),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::ffi;
+    use test_log::test;
+
+    use super::Error;
+
+    fn sqlite_error(code: std::ffi::c_int) -> Error {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(code), None).into()
+    }
+
+    #[test]
+    fn test_sqlite_errno() {
+        let cases = [
+            (ffi::SQLITE_FULL, libc::ENOSPC),
+            (ffi::SQLITE_BUSY, libc::EAGAIN),
+            (ffi::SQLITE_LOCKED, libc::EBUSY),
+            (ffi::SQLITE_READONLY, libc::EROFS),
+            (ffi::SQLITE_IOERR, libc::EIO),
+            (ffi::SQLITE_IOERR_SHORT_READ, libc::EIO),
+            (ffi::SQLITE_CORRUPT, libc::EIO),
+            (ffi::SQLITE_NOTADB, libc::EIO),
+            (ffi::SQLITE_CONSTRAINT, libc::EEXIST),
+            (ffi::SQLITE_CONSTRAINT_UNIQUE, libc::EEXIST),
+            (ffi::SQLITE_MISUSE, libc::ENOTSUP),
+        ];
+        for (code, errno) in cases {
+            assert_eq!(sqlite_error(code).errno(), errno, "SQLite error code {code}");
+        }
+        assert_eq!(Error::from(rusqlite::Error::QueryReturnedNoRows), Error::NotFound);
+    }
+
+    #[test]
+    fn test_sqlite_statement_errors() -> anyhow::Result<()> {
+        let db = rusqlite::Connection::open_in_memory()?;
+        db.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1);")?;
+
+        let err = db.execute("INSERT INTO t VALUES (1)", []).unwrap_err();
+        assert_eq!(Error::from(err), Error::AlreadyExists);
+
+        db.pragma_update(None, "query_only", true)?;
+        let err = db.execute("INSERT INTO t VALUES (2)", []).unwrap_err();
+        assert_eq!(Error::from(err), Error::ReadOnly);
+        Ok(())
+    }
+}
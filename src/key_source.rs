@@ -0,0 +1,140 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+/// Where the key of a database is read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// Given on the command line, visible to the other users in the process list.
+    Arg(String),
+    File(PathBuf),
+    /// The name of an environment variable.
+    Env(String),
+    /// A file descriptor inherited from the parent process, read until its end then closed.
+    Fd(RawFd),
+    Stdin,
+    /// Asked on the terminal without echo.
+    Prompt,
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Arg(_) => write!(f, "--key"),
+            KeySource::File(path) => write!(f, "key file {}", path.display()),
+            KeySource::Env(var) => write!(f, "environment variable {}", var),
+            KeySource::Fd(fd) => write!(f, "file descriptor {}", fd),
+            KeySource::Stdin => write!(f, "stdin"),
+            KeySource::Prompt => write!(f, "prompt"),
+        }
+    }
+}
+
+impl KeySource {
+    /// Reads the key, without the trailing newline of files and streams. With `confirm` the prompt asks for the key
+    /// twice, for keys of new databases. An empty key is an error.
+    pub fn read(self, confirm: bool) -> anyhow::Result<String> {
+        let source = self.to_string();
+        let key = match self {
+            KeySource::Arg(key) => key,
+            KeySource::File(path) => {
+                let raw_key = fs::read_to_string(&path).with_context(|| format!("unable to read {}", source))?;
+                raw_key.trim_end().to_owned()
+            }
+            KeySource::Env(var) => match std::env::var(&var) {
+                Ok(key) => key,
+                Err(std::env::VarError::NotPresent) => bail!("Environment variable {} is not set", var),
+                Err(e) => bail!("Environment variable {}: {}", var, e),
+            },
+            KeySource::Fd(fd) => {
+                // SAFETY: fcntl only checks that the descriptor is open, before the File takes ownership of it.
+                if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                    bail!("File descriptor {} is not open", fd);
+                }
+                // SAFETY: the descriptor is open and not used anywhere else in the process.
+                let mut file = unsafe { File::from_raw_fd(fd) };
+                let mut raw_key = String::new();
+                file.read_to_string(&mut raw_key)
+                    .with_context(|| format!("unable to read {}", source))?;
+                raw_key.trim_end().to_owned()
+            }
+            KeySource::Stdin => {
+                let mut raw_key = String::new();
+                io::stdin()
+                    .read_to_string(&mut raw_key)
+                    .with_context(|| format!("unable to read {}", source))?;
+                raw_key.trim_end().to_owned()
+            }
+            KeySource::Prompt => {
+                let key = rpassword::prompt_password("Key: ").context("unable to read the key from the terminal")?;
+                if confirm && !key.is_empty() {
+                    let again = rpassword::prompt_password("Confirm key: ")
+                        .context("unable to read the key from the terminal")?;
+                    if again != key {
+                        bail!("Keys do not match");
+                    }
+                }
+                key
+            }
+        };
+
+        if key.is_empty() {
+            bail!("Key from {} is empty", source);
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, Write};
+    use std::os::fd::IntoRawFd;
+
+    use test_log::test;
+
+    use super::KeySource;
+
+    #[test]
+    fn test_key_sources() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("key");
+        std::fs::write(&path, "from file\n")?;
+        assert_eq!(KeySource::File(path.clone()).read(false)?, "from file");
+        std::fs::write(&path, "\n")?;
+        let err = KeySource::File(path.clone()).read(false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Key from key file {} is empty", path.display())
+        );
+        assert!(KeySource::File(tmp.path().join("missing")).read(false).is_err());
+
+        std::env::set_var("NIGHTSHIFT_TEST_KEY", "from env");
+        assert_eq!(
+            KeySource::Env("NIGHTSHIFT_TEST_KEY".to_owned()).read(false)?,
+            "from env"
+        );
+        let err = KeySource::Env("NIGHTSHIFT_TEST_MISSING".to_owned())
+            .read(false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable NIGHTSHIFT_TEST_MISSING is not set"
+        );
+
+        let mut file = tempfile::tempfile()?;
+        file.write_all(b"from fd\n")?;
+        file.rewind()?;
+        let fd = file.into_raw_fd();
+        assert_eq!(KeySource::Fd(fd).read(false)?, "from fd");
+        // A closed descriptor could be reused by another test, a negative one is never open.
+        let err = KeySource::Fd(-1).read(false).unwrap_err();
+        assert_eq!(err.to_string(), "File descriptor -1 is not open");
+
+        let err = KeySource::Arg(String::new()).read(false).unwrap_err();
+        assert_eq!(err.to_string(), "Key from --key is empty");
+        Ok(())
+    }
+}
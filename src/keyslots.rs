@@ -0,0 +1,288 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::time::TimeSpec;
+
+const VERSION: u32 = 1;
+const MASTER_KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Returns the path of the key slots of the database at `database`, stored next to it in plaintext since the database
+/// itself cannot be read without the key.
+pub fn header_path(database: &Path) -> PathBuf {
+    let mut name = OsString::from(database.as_os_str());
+    name.push(".keyslots");
+    PathBuf::from(name)
+}
+
+fn random<const N: usize>() -> anyhow::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("unable to generate random bytes: {e}"))?;
+    Ok(bytes)
+}
+
+/// Random key of the database, used as the raw SQLCipher key and wrapped by every key slot.
+pub struct MasterKey(Zeroizing<[u8; MASTER_KEY_LEN]>);
+
+impl MasterKey {
+    pub fn generate() -> anyhow::Result<MasterKey> {
+        Ok(MasterKey(Zeroizing::new(random()?)))
+    }
+
+    /// Returns the key in the `x'...'` notation SQLCipher uses for raw keys, which skips its own key derivation.
+    pub fn sqlcipher_key(&self) -> String {
+        format!("x'{}'", hex::encode_upper(self.0.as_slice()))
+    }
+}
+
+/// Derivation of the key encrypting the master key from a passphrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Kdf {
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Default for Kdf {
+    /// The parameters recommended by OWASP for Argon2id.
+    fn default() -> Self {
+        Kdf::Argon2id {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Kdf {
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> anyhow::Result<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0; 32]);
+        match *self {
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(key.len()))
+                    .map_err(|e| anyhow!("invalid argon2 parameters: {e}"))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+                    .map_err(|e| anyhow!("argon2: {e}"))?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl std::fmt::Display for Kdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => write!(f, "argon2id m={memory_kib}KiB t={iterations} p={parallelism}"),
+        }
+    }
+}
+
+/// The master key encrypted with ChaCha20-Poly1305 by a key derived from one passphrase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub id: u32,
+    pub label: Option<String>,
+    pub created_at: u64,
+    pub kdf: Kdf,
+    salt: String,
+    nonce: String,
+    wrapped_key: String,
+}
+
+impl KeySlot {
+    fn unwrap_key(&self, passphrase: &str) -> anyhow::Result<Option<MasterKey>> {
+        let salt = hex::decode(&self.salt)?;
+        let nonce = hex::decode(&self.nonce)?;
+        let wrapped_key = hex::decode(&self.wrapped_key)?;
+        if nonce.len() != NONCE_LEN {
+            bail!("key slot {} is damaged", self.id);
+        }
+        let kek = self.kdf.derive(passphrase, &salt)?;
+        let payload = Payload {
+            msg: &wrapped_key,
+            aad: &self.id.to_le_bytes(),
+        };
+        // Authentication fails with the wrong passphrase.
+        let Ok(key) =
+            ChaCha20Poly1305::new(Key::from_slice(kek.as_slice())).decrypt(Nonce::from_slice(&nonce), payload)
+        else {
+            return Ok(None);
+        };
+        let key = Zeroizing::new(key);
+        let key: [u8; MASTER_KEY_LEN] = key
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("key slot {} is damaged", self.id))?;
+        Ok(Some(MasterKey(Zeroizing::new(key))))
+    }
+}
+
+/// Key slots of a database, like the LUKS header: any passphrase of a slot unlocks the master key, and passphrases are
+/// added or removed without re-encrypting the database.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeySlots {
+    version: u32,
+    pub slots: Vec<KeySlot>,
+}
+
+impl KeySlots {
+    pub fn new() -> KeySlots {
+        KeySlots {
+            version: VERSION,
+            slots: Vec::new(),
+        }
+    }
+
+    /// Reads the key slots of the database at `database`, `None` when it does not use any.
+    pub fn load(database: &Path) -> anyhow::Result<Option<KeySlots>> {
+        let path = header_path(database);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("unable to read {}", path.display())),
+        };
+        let slots: KeySlots =
+            serde_json::from_slice(&data).with_context(|| format!("unable to parse {}", path.display()))?;
+        if slots.version != VERSION {
+            bail!("{}: unsupported key slots version {}", path.display(), slots.version);
+        }
+        Ok(Some(slots))
+    }
+
+    /// Replaces the key slots of the database at `database`, readable by the owner only.
+    pub fn save(&self, database: &Path) -> anyhow::Result<()> {
+        let path = header_path(database);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("unable to write {}", path.display()))?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Returns the master key from the first slot `passphrase` unlocks.
+    pub fn unlock(&self, passphrase: &str) -> anyhow::Result<MasterKey> {
+        for slot in &self.slots {
+            if let Some(key) = slot.unwrap_key(passphrase)? {
+                log::debug!("Unlocked key slot {}", slot.id);
+                return Ok(key);
+            }
+        }
+        bail!("Invalid key: no key slot matches it")
+    }
+
+    /// Wraps `master` with `passphrase` in a new slot and returns its id.
+    pub fn add(
+        &mut self,
+        master: &MasterKey,
+        passphrase: &str,
+        label: Option<String>,
+        kdf: Kdf,
+    ) -> anyhow::Result<u32> {
+        let id = self.slots.iter().map(|slot| slot.id + 1).max().unwrap_or(0);
+        let salt: [u8; SALT_LEN] = random()?;
+        let nonce: [u8; NONCE_LEN] = random()?;
+        let kek = kdf.derive(passphrase, &salt)?;
+        let payload = Payload {
+            msg: master.0.as_slice(),
+            aad: &id.to_le_bytes(),
+        };
+        let wrapped_key = ChaCha20Poly1305::new(Key::from_slice(kek.as_slice()))
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|e| anyhow!("unable to wrap the key: {e}"))?;
+        self.slots.push(KeySlot {
+            id,
+            label,
+            created_at: TimeSpec::from(SystemTime::now()).secs,
+            kdf,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            wrapped_key: hex::encode(wrapped_key),
+        });
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: u32) -> anyhow::Result<()> {
+        let Some(index) = self.slots.iter().position(|slot| slot.id == id) else {
+            bail!("No key slot {id}");
+        };
+        if self.slots.len() == 1 {
+            bail!("Cannot remove the last key slot");
+        }
+        self.slots.remove(index);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::{Kdf, KeySlots, MasterKey};
+
+    const TEST_KDF: Kdf = Kdf::Argon2id {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_key_slots() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let database = tmp.path().join("db.sqlite");
+        assert!(KeySlots::load(&database)?.is_none());
+
+        let master = MasterKey::generate()?;
+        assert_eq!(master.sqlcipher_key().len(), 2 * 32 + 3);
+        let mut slots = KeySlots::new();
+        assert_eq!(slots.add(&master, "alice", Some("Alice".to_owned()), TEST_KDF)?, 0);
+        assert_eq!(slots.add(&master, "bob", None, TEST_KDF)?, 1);
+        slots.save(&database)?;
+
+        let mut slots = KeySlots::load(&database)?.expect("key slots");
+        assert_eq!(slots.unlock("alice")?.sqlcipher_key(), master.sqlcipher_key());
+        assert_eq!(slots.unlock("bob")?.sqlcipher_key(), master.sqlcipher_key());
+        assert!(slots.unlock("eve").is_err());
+
+        slots.remove(0)?;
+        assert!(slots.unlock("alice").is_err());
+        assert!(slots.remove(0).is_err());
+        assert!(slots.remove(1).is_err());
+        assert_eq!(slots.add(&master, "carol", None, TEST_KDF)?, 2);
+
+        // A slot cannot be moved to another id.
+        slots.slots[1].id = 7;
+        assert!(slots.unlock("carol").is_err());
+        Ok(())
+    }
+}
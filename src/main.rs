@@ -1,15 +1,23 @@
 #![allow(clippy::too_many_arguments)]
 
+mod cipher;
+mod commands;
 mod database;
 mod driver;
 mod errors;
+mod key_source;
+mod keyslots;
 mod queries;
 mod time;
 mod types;
 
+#[cfg(test)]
+mod test_util;
+
 use std::{
-    fs,
-    path::PathBuf,
+    io::{self, Write},
+    os::fd::RawFd,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -21,11 +29,15 @@ use std::{
 
 use anyhow::{bail, Context};
 use clap::{Parser, Subcommand};
-use queries::block::Compression;
+use queries::block::{CompressionSpec, DEFAULT_BLOCK_SIZE, DEFAULT_MIN_SAVINGS};
 use scopeguard::defer;
 
-use crate::database::DatabaseOps;
+use crate::cipher::{CipherSettings, HmacAlgorithm};
+use crate::commands::backup::{BackupKey, BackupSteps};
+use crate::database::{CreateOptions, DatabaseOps};
 use crate::driver::FuseDriver;
+use crate::key_source::KeySource;
+use crate::keyslots::{Kdf, KeySlots};
 use simple_logger::SimpleLogger;
 
 #[derive(Parser, Debug)]
@@ -39,6 +51,97 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Create a new database.
+    Init {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long = "block-size", help = "Block size in bytes, accepts K and M suffixes", value_parser = parse_size, default_value_t = DEFAULT_BLOCK_SIZE)]
+        block_size: u64,
+
+        #[arg(
+            long = "compress",
+            short = 'c',
+            help = "Default compression: none, lz4, zstd, zstd-dict, xz, brotli, auto or <zstd|zstd-dict|auto>:<level>[:long]",
+            default_value = "lz4"
+        )]
+        compression: CompressionSpec,
+
+        #[arg(long = "min-savings", help = "Store blocks uncompressed unless compression saves this percentage", value_parser = clap::value_parser!(u8).range(0..100), default_value_t = DEFAULT_MIN_SAVINGS)]
+        min_savings: u8,
+
+        #[arg(long, help = "Human readable name of the filesystem")]
+        label: Option<String>,
+
+        #[clap(flatten)]
+        cipher: CipherArgs,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Print the metadata of the database.
+    Info {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Change the label of the database.
+    Label {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(help = "New label")]
+        label: String,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Record the SQLCipher settings of an encrypted database, such as one created by another SQLCipher version. They are
+    /// checked by opening the database with them, no option restores the defaults.
+    SetCipher {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        cipher: CipherArgs,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Change the compression used for new blocks when mounting without `--compress`, or the compression policy of a
+    /// directory inherited by the files created beneath it.
+    SetCompression {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(
+            help = "Compression: none, lz4, zstd, zstd-dict, xz, brotli, auto or <zstd|zstd-dict|auto>:<level>[:long]",
+            required_unless_present = "unset"
+        )]
+        compression: Option<CompressionSpec>,
+
+        #[arg(
+            long,
+            help = "Set the policy of this file or directory instead of the database default"
+        )]
+        path: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Remove the policy of --path",
+            requires = "path",
+            conflicts_with = "compression"
+        )]
+        unset: bool,
+
+        #[arg(long = "min-savings", help = "Store blocks uncompressed unless compression saves this percentage", value_parser = clap::value_parser!(u8).range(0..100))]
+        min_savings: Option<u8>,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
     /// Mount the database on a directory.
     Mount {
         #[arg(long = "db", help = "Database file path")]
@@ -47,8 +150,12 @@ enum Commands {
         #[arg(long = "mount", help = "Path where filesystem will be mounted")]
         mount_path: PathBuf,
 
-        #[arg(long = "compress", short = 'c', help = "Compression algorithm")]
-        compression: Option<Compression>,
+        #[arg(
+            long = "compress",
+            short = 'c',
+            help = "Compression: none, lz4, zstd, zstd-dict, xz, brotli, auto or <zstd|zstd-dict|auto>:<level>[:long], defaults to the one stored in the database, stored as its default when the database is created"
+        )]
+        compression: Option<CompressionSpec>,
 
         #[clap(flatten)]
         key_group: KeyGroup,
@@ -60,8 +167,12 @@ enum Commands {
         #[arg(long = "mount", help = "Path where filesystem will be mounted")]
         mount_path: PathBuf,
 
-        #[arg(long = "compress", short = 'c', help = "Compression algorithm")]
-        compression: Option<Compression>,
+        #[arg(
+            long = "compress",
+            short = 'c',
+            help = "Compression: none, lz4, zstd, zstd-dict, xz, brotli, auto or <zstd|zstd-dict|auto>:<level>[:long], defaults to the one stored in the database, stored as its default when the database is created"
+        )]
+        compression: Option<CompressionSpec>,
 
         #[clap(flatten)]
         key_group: KeyGroup,
@@ -80,34 +191,443 @@ enum Commands {
         #[clap(flatten)]
         key_group: KeyGroup,
     },
+    /// Rewrite every file of the database using a new block size.
+    Repack {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long = "block-size", help = "Block size in bytes, accepts K and M suffixes", value_parser = parse_size)]
+        block_size: u64,
+
+        #[arg(
+            long = "compress",
+            short = 'c',
+            help = "Compression: none, lz4, zstd, zstd-dict, xz, brotli, auto or <zstd|zstd-dict|auto>:<level>[:long], defaults to the one stored in the database"
+        )]
+        compression: Option<CompressionSpec>,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Rewrite every block of the database with a new compression. Can be interrupted and resumed.
+    Recompress {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(
+            long = "to",
+            help = "Compression: none, lz4, zstd, zstd-dict, xz, brotli, auto or <zstd|zstd-dict|auto>:<level>[:long]"
+        )]
+        compression: CompressionSpec,
+
+        #[arg(
+            long = "batch-size",
+            help = "Blocks rewritten per transaction",
+            default_value_t = 256
+        )]
+        batch_size: usize,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Check the structure of the filesystem: orphan inodes, dangling or duplicate entries, link counts and sizes.
+    Fsck {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Fix the problems found, unreachable inodes are moved to /lost+found")]
+        repair: bool,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Decompress and verify every block, then check the integrity of the database file. Prints a JSON report and
+    /// exits with an error when a problem is found.
+    Scrub {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// List a directory of the database without mounting it.
+    Ls {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Path inside the filesystem", default_value = "/")]
+        path: PathBuf,
+
+        #[arg(short = 'l', help = "Print permissions, owners, sizes and modification times")]
+        long: bool,
+
+        #[arg(short = 'R', help = "List subdirectories recursively")]
+        recursive: bool,
+
+        #[arg(long, help = "Print the attributes of the entries as JSON")]
+        json: bool,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Print the attributes of a file of the database without mounting it.
+    Stat {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Path inside the filesystem")]
+        path: PathBuf,
+
+        #[arg(long, help = "Print the attributes as JSON")]
+        json: bool,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Copy a file or directory out of the database without mounting it.
+    Get {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Path inside the filesystem")]
+        path: PathBuf,
+
+        #[arg(long, help = "Destination on the host, directories are copied recursively")]
+        out: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Write the content of a file of the database to stdout without mounting it.
+    Cat {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Path inside the filesystem")]
+        path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Copy a host file or directory into the database without mounting it.
+    Put {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "File or directory on the host, directories are copied recursively")]
+        src: PathBuf,
+
+        #[arg(long, help = "Destination path inside the filesystem")]
+        dest: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Write the whole filesystem to a tar archive without mounting it.
+    ExportTar {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Archive to create, compressed with zstd when its name ends with .zst")]
+        out: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Copy the entries of a tar archive into the database without mounting it.
+    ImportTar {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Archive to read, decompressed with zstd when its name ends with .zst")]
+        src: PathBuf,
+
+        #[arg(long, default_value = "/", help = "Directory inside the filesystem to import into")]
+        dest: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Write the whole filesystem to the sqlar table of a SQLite database, readable by `sqlite3 -A`.
+    ExportSqlar {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "SQLite archive to write, created if it does not exist")]
+        out: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Copy the entries of a SQLite archive created by `sqlite3 -A` into the database.
+    ImportSqlar {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "SQLite archive to read")]
+        src: PathBuf,
+
+        #[arg(long, default_value = "/", help = "Directory inside the filesystem to import into")]
+        dest: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Copy the database with the SQLite online backup API, consistent even while it is mounted.
+    Backup {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Backup file to create")]
+        out: PathBuf,
+
+        #[arg(
+            long,
+            help = "Pages copied per step, the database is only locked during a step [default: all at once]"
+        )]
+        step_pages: Option<u32>,
+
+        #[arg(long, default_value_t = 0, help = "Pause between steps in milliseconds")]
+        step_pause: u64,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[clap(flatten)]
+        new_key_group: NewKeyGroup,
+    },
+    /// Change the encryption key of an unmounted database, or encrypt or decrypt it.
+    Rekey {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[clap(flatten)]
+        new_key_group: NewKeyGroup,
+    },
+    /// Manage the key slots of a database, each wrapping its master key with another key.
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// Train a zstd dictionary on the data of the database, used by the zstd-dict compression.
+    TrainDict {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Maximum number of blocks to sample", default_value_t = 1000)]
+        samples: usize,
+
+        #[arg(long = "max-size", help = "Maximum dictionary size in bytes, accepts K and M suffixes", value_parser = parse_size, default_value_t = 112 * 1024)]
+        max_size: u64,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match value.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (value, 1),
+        },
+    };
+    digits
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|e| format!("invalid size {value:?}: {e}"))
+}
+
+#[derive(Debug, Subcommand)]
+enum KeyCommands {
+    /// Add a key slot. A database without key slots is first encrypted with a random master key, its current key
+    /// getting the first slot.
+    Add {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Name of the slot, to tell who it belongs to")]
+        label: Option<String>,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[clap(flatten)]
+        new_key_group: NewKeyGroup,
+    },
+    /// Remove a key slot, with the key of any slot.
+    Remove {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[arg(long, help = "Id of the slot to remove")]
+        slot: u32,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// List the key slots, no key is needed.
+    List {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+    },
+}
+
+/// SQLCipher settings of an encrypted database, saved next to it.
+#[derive(Debug, clap::Args)]
+struct CipherArgs {
+    #[arg(long, value_name = "VERSION", help = "Use the defaults of SQLCipher 1, 2, 3 or 4", value_parser = clap::value_parser!(u8).range(1..=4))]
+    cipher_compatibility: Option<u8>,
+
+    #[arg(long, help = "PBKDF2 iterations deriving the encryption key from the key")]
+    kdf_iter: Option<u32>,
+
+    #[arg(long, help = "Size of the encrypted pages, a power of two between 512 and 65536", value_parser = parse_size)]
+    cipher_page_size: Option<u64>,
+
+    #[arg(long, help = "HMAC algorithm authenticating the pages: sha1, sha256 or sha512")]
+    hmac_algorithm: Option<HmacAlgorithm>,
+
+    #[arg(
+        long,
+        help = "The key is 64 hex digits, or 96 with the salt, used without key derivation"
+    )]
+    raw_key: bool,
+}
+
+impl CipherArgs {
+    fn settings(self) -> anyhow::Result<CipherSettings> {
+        let page_size = self
+            .cipher_page_size
+            .map(u32::try_from)
+            .transpose()
+            .context("invalid cipher page size")?;
+        let settings = CipherSettings {
+            compatibility: self.cipher_compatibility,
+            kdf_iter: self.kdf_iter,
+            page_size,
+            hmac_algorithm: self.hmac_algorithm,
+            raw_key: self.raw_key,
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
 }
 
 #[derive(Debug, clap::Args)]
 #[group(multiple = false)]
 struct KeyGroup {
-    #[arg(long, help = "Decryption key")]
+    #[arg(long, help = "Decryption key, visible to other users in the process list")]
     key: Option<String>,
 
     #[arg(long, help = "Path to file containing decryption key")]
     key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "VAR",
+        help = "Read the decryption key from an environment variable"
+    )]
+    key_env: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FD",
+        help = "Read the decryption key from an open file descriptor"
+    )]
+    key_fd: Option<RawFd>,
+
+    #[arg(long, help = "Read the decryption key from stdin")]
+    key_stdin: bool,
+
+    #[arg(long, help = "Prompt for the decryption key on the terminal")]
+    key_prompt: bool,
 }
 
 impl KeyGroup {
-    fn read_key(self) -> anyhow::Result<Option<String>> {
-        let key = if let Some(key) = self.key {
-            key
+    fn source(self) -> Option<KeySource> {
+        if let Some(key) = self.key {
+            Some(KeySource::Arg(key))
         } else if let Some(key_file) = self.key_file {
-            let raw_key = fs::read_to_string(key_file)?;
-            raw_key.trim_end().to_owned()
+            Some(KeySource::File(key_file))
+        } else if let Some(var) = self.key_env {
+            Some(KeySource::Env(var))
+        } else if let Some(fd) = self.key_fd {
+            Some(KeySource::Fd(fd))
+        } else if self.key_stdin {
+            Some(KeySource::Stdin)
+        } else if self.key_prompt {
+            Some(KeySource::Prompt)
         } else {
+            None
+        }
+    }
+
+    /// Returns the key of the database at `database_path`: the key given, or the master key it unlocks when the
+    /// database uses key slots.
+    fn read_key(self, database_path: &Path) -> anyhow::Result<Option<String>> {
+        let Some(passphrase) = self.read_passphrase()? else {
             return Ok(None);
         };
-
-        if key.is_empty() {
-            bail!("Key cannot be empty");
+        match KeySlots::load(database_path)? {
+            Some(slots) => Ok(Some(slots.unlock(&passphrase)?.sqlcipher_key())),
+            None => Ok(Some(passphrase)),
         }
+    }
+
+    fn read_passphrase(self) -> anyhow::Result<Option<String>> {
+        self.source().map(|source| source.read(false)).transpose()
+    }
 
-        Ok(Some(key))
+    /// Returns the key of a new database, asked twice when prompted.
+    fn read_new_key(self) -> anyhow::Result<Option<String>> {
+        self.source().map(|source| source.read(true)).transpose()
+    }
+}
+
+/// Encryption of a copy of the database, the key of the source by default.
+#[derive(Debug, clap::Args)]
+#[group(multiple = false)]
+struct NewKeyGroup {
+    #[arg(long, help = "Encryption key of the copy")]
+    new_key: Option<String>,
+
+    #[arg(long, help = "Path to file containing the encryption key of the copy")]
+    new_key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "VAR",
+        help = "Read the encryption key of the copy from an environment variable"
+    )]
+    new_key_env: Option<String>,
+
+    #[arg(long, help = "Prompt for the encryption key of the copy on the terminal")]
+    new_key_prompt: bool,
+
+    #[arg(long, help = "Write the copy in plaintext")]
+    decrypt: bool,
+}
+
+impl NewKeyGroup {
+    fn read_key(self) -> anyhow::Result<BackupKey> {
+        if self.decrypt {
+            return Ok(BackupKey::Plaintext);
+        }
+        let key = KeyGroup {
+            key: self.new_key,
+            key_file: self.new_key_file,
+            key_env: self.new_key_env,
+            key_fd: None,
+            key_stdin: false,
+            key_prompt: self.new_key_prompt,
+        }
+        .read_new_key()?;
+        Ok(key.map_or(BackupKey::Same, BackupKey::Key))
     }
 }
 
@@ -120,15 +640,101 @@ fn main() -> anyhow::Result<()> {
         .context("unable to install logging")?;
 
     match args.command {
+        Commands::Init {
+            database_path,
+            block_size,
+            compression,
+            min_savings,
+            label,
+            cipher,
+            key_group,
+        } => {
+            let key = key_group.read_new_key()?;
+            let options = CreateOptions {
+                block_size,
+                compression,
+                min_savings,
+                label,
+                cipher: cipher.settings()?,
+            };
+            DatabaseOps::create(&database_path, key, &options).context("create db")?;
+            println!(
+                "Created {} with a block size of {} bytes",
+                database_path.display(),
+                block_size
+            );
+        }
+        Commands::Info {
+            database_path,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            for (key, value) in db.with_read_tx(queries::meta::list)? {
+                println!("{key}: {value}");
+            }
+            if !db.cipher.is_default() {
+                println!("cipher: {}", db.cipher);
+            }
+        }
+        Commands::SetCipher {
+            database_path,
+            cipher,
+            key_group,
+        } => {
+            let cipher = cipher.settings()?;
+            let key = key_group.read_key(&database_path)?.context("A key is required")?;
+            DatabaseOps::open_with(&database_path, Some(key), cipher).context("open db")?;
+            cipher.save(&database_path)?;
+        }
+        Commands::Label {
+            database_path,
+            label,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            db.with_write_tx(|tx| queries::meta::set(tx, queries::meta::LABEL, &label))?;
+        }
+        Commands::SetCompression {
+            database_path,
+            compression,
+            path,
+            unset: _,
+            min_savings,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            db.with_write_tx(|tx| {
+                match (&path, compression) {
+                    (Some(path), compression) => {
+                        let ino = queries::dir_entry::resolve(tx, path)?;
+                        if let Some(compression) = compression {
+                            queries::meta::enable_compression(tx, compression)?;
+                        }
+                        queries::inode::set_compression(tx, ino, compression)?;
+                    }
+                    (None, Some(compression)) => queries::meta::set_compression(tx, compression)?,
+                    (None, None) => {}
+                }
+                if let Some(min_savings) = min_savings {
+                    queries::meta::set(tx, queries::meta::MIN_SAVINGS, min_savings)?;
+                }
+                Ok(())
+            })
+            .context("unable to set compression")?;
+        }
         Commands::Mount {
             database_path,
             mount_path,
             compression,
             key_group,
         } => {
-            let key = key_group.read_key()?;
-            let db = DatabaseOps::open(&database_path, key).context("open db")?;
-            let driver = FuseDriver::new(db, compression.unwrap_or_default(), &mount_path)?;
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open_or_create(&database_path, key, compression).context("open db")?;
+            let compression = compression.map_or_else(|| db.compression(), Ok)?;
+            let driver = FuseDriver::new(db, compression, &mount_path)?;
 
             let mount = fuser::spawn_mount2(driver, &mount_path, &[]).context("unable to create mount")?;
             defer! {
@@ -151,9 +757,10 @@ fn main() -> anyhow::Result<()> {
             cmd,
             args,
         } => {
-            let key = key_group.read_key()?;
-            let db = DatabaseOps::open(&database_path, key).context("open db")?;
-            let driver = FuseDriver::new(db, compression.unwrap_or_default(), &mount_path)?;
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open_or_create(&database_path, key, compression).context("open db")?;
+            let compression = compression.map_or_else(|| db.compression(), Ok)?;
+            let driver = FuseDriver::new(db, compression, &mount_path)?;
             let mount = fuser::spawn_mount2(driver, &mount_path, &[]).context("unable to create mount")?;
             defer! {
                 // Umount & cleanup
@@ -184,12 +791,330 @@ fn main() -> anyhow::Result<()> {
             database_path,
             key_group,
         } => {
-            let key = key_group.read_key()?;
+            let key = key_group.read_key(&database_path)?;
             let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
             println!("Running VACUUM on database, this may take a few seconds...");
             db.vacuum()?;
             println!("Done!");
         }
+        Commands::Repack {
+            database_path,
+            block_size,
+            compression,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            println!("Repacking database to a block size of {} bytes...", block_size);
+            let compression = compression.map_or_else(|| db.compression(), Ok)?;
+            let files = commands::repack::repack(&mut db, block_size, compression)?;
+            println!("Done! Repacked {} files, run optimize to reclaim disk space.", files);
+        }
+        Commands::Recompress {
+            database_path,
+            compression,
+            batch_size,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            println!("Recompressing database to {}...", compression);
+            let stats = commands::recompress::recompress(&mut db, compression, batch_size, |stats| {
+                println!("{}/{} blocks", stats.done, stats.total);
+            })?;
+            let saved = stats.bytes_before as i64 - stats.bytes_after as i64;
+            println!(
+                "Done! {} bytes before, {} bytes after, {} bytes saved. Run optimize to reclaim disk space.",
+                stats.bytes_before, stats.bytes_after, saved
+            );
+        }
+        Commands::Fsck {
+            database_path,
+            repair,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let problems = commands::fsck::fsck(&mut db, repair)?;
+            for problem in &problems {
+                println!("{problem}");
+            }
+            match (problems.len(), repair) {
+                (0, _) => println!("No problems found."),
+                (count, true) => println!("Repaired {} problems.", count),
+                (count, false) => bail!("{} problems found, run with --repair to fix them", count),
+            }
+        }
+        Commands::Scrub {
+            database_path,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let report = commands::scrub::scrub(&mut db)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.problems.is_empty() {
+                bail!("{} problems found", report.problems.len());
+            }
+        }
+        Commands::Ls {
+            database_path,
+            path,
+            long,
+            recursive,
+            json,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::ls::ls(&mut db, &path, recursive).context("unable to list directory")?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                for stat in &stats {
+                    let name = if recursive { stat.path.as_str() } else { stat.name() };
+                    match long {
+                        true => println!("{}", stat.long(name)),
+                        false => println!("{}", name),
+                    }
+                }
+            }
+        }
+        Commands::Stat {
+            database_path,
+            path,
+            json,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stat = commands::ls::stat(&mut db, &path).context("unable to stat file")?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&stat)?),
+                false => println!("{}", stat),
+            }
+        }
+        Commands::Get {
+            database_path,
+            path,
+            out,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::get::get(&mut db, &path, &out)
+                .with_context(|| format!("unable to extract {}", path.display()))?;
+            println!(
+                "Extracted {} files and {} directories, {} bytes.",
+                stats.files, stats.directories, stats.bytes
+            );
+        }
+        Commands::Cat {
+            database_path,
+            path,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            commands::get::cat(&mut db, &path, &mut out)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            out.flush()?;
+        }
+        Commands::Put {
+            database_path,
+            src,
+            dest,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::put::put(&mut db, &src, &dest, |stats| {
+                eprint!(
+                    "\r{} files, {} directories, {} bytes",
+                    stats.files, stats.directories, stats.bytes
+                );
+            })?;
+            eprintln!();
+            println!(
+                "Imported {} files and {} directories, {} bytes.",
+                stats.files, stats.directories, stats.bytes
+            );
+        }
+        Commands::ExportTar {
+            database_path,
+            out,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::tar::export_tar(&mut db, &out)
+                .with_context(|| format!("unable to export to {}", out.display()))?;
+            println!(
+                "Exported {} files and {} directories, {} bytes.",
+                stats.files, stats.directories, stats.bytes
+            );
+        }
+        Commands::ImportTar {
+            database_path,
+            src,
+            dest,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::tar::import_tar(&mut db, &src, &dest, |stats| {
+                eprint!(
+                    "\r{} files, {} directories, {} bytes",
+                    stats.files, stats.directories, stats.bytes
+                );
+            })?;
+            eprintln!();
+            println!(
+                "Imported {} files and {} directories, {} bytes.",
+                stats.files, stats.directories, stats.bytes
+            );
+        }
+        Commands::ExportSqlar {
+            database_path,
+            out,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::sqlar::export_sqlar(&mut db, &out)
+                .with_context(|| format!("unable to export to {}", out.display()))?;
+            println!(
+                "Exported {} files and {} directories, {} bytes.",
+                stats.files, stats.directories, stats.bytes
+            );
+        }
+        Commands::ImportSqlar {
+            database_path,
+            src,
+            dest,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let stats = commands::sqlar::import_sqlar(&mut db, &src, &dest, |stats| {
+                eprint!(
+                    "\r{} files, {} directories, {} bytes",
+                    stats.files, stats.directories, stats.bytes
+                );
+            })?;
+            eprintln!();
+            println!(
+                "Imported {} files and {} directories, {} bytes.",
+                stats.files, stats.directories, stats.bytes
+            );
+        }
+        Commands::Backup {
+            database_path,
+            out,
+            step_pages,
+            step_pause,
+            key_group,
+            new_key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let backup_key = new_key_group.read_key()?;
+            let slots = match backup_key {
+                BackupKey::Same => KeySlots::load(&database_path)?,
+                _ => None,
+            };
+            let mut db = DatabaseOps::open(&database_path, key.clone()).context("open db")?;
+            let steps = BackupSteps {
+                pages: step_pages,
+                pause: Duration::from_millis(step_pause),
+            };
+            commands::backup::backup(&mut db, key, &out, backup_key, steps, |progress| {
+                eprint!(
+                    "\r{} of {} pages",
+                    progress.pagecount - progress.remaining,
+                    progress.pagecount
+                );
+            })
+            .with_context(|| format!("unable to back up to {}", out.display()))?;
+            eprintln!();
+            // The copy has the same master key, it opens with the same key slots.
+            if let Some(slots) = slots {
+                slots.save(&out)?;
+            }
+            println!("Backed up {} to {}.", database_path.display(), out.display());
+        }
+        Commands::Rekey {
+            database_path,
+            key_group,
+            new_key_group,
+        } => {
+            if KeySlots::load(&database_path)?.is_some() {
+                bail!("Database uses key slots, change them with key add and key remove");
+            }
+            let key = key_group.read_key(&database_path)?;
+            let new_key = match new_key_group.read_key()? {
+                BackupKey::Same => {
+                    bail!("Either --new-key, --new-key-file, --new-key-env, --new-key-prompt or --decrypt is required")
+                }
+                BackupKey::Key(key) => Some(key),
+                BackupKey::Plaintext => None,
+            };
+            commands::rekey::rekey(&database_path, key, new_key)?;
+            println!("Changed the key of {}.", database_path.display());
+        }
+        Commands::Key { command } => match command {
+            KeyCommands::Add {
+                database_path,
+                label,
+                key_group,
+                new_key_group,
+            } => {
+                let passphrase = key_group.read_passphrase()?;
+                let new_passphrase = match new_key_group.read_key()? {
+                    BackupKey::Key(new_passphrase) => new_passphrase,
+                    BackupKey::Plaintext => bail!("--decrypt cannot be used with key slots"),
+                    BackupKey::Same => {
+                        bail!("Either --new-key, --new-key-file, --new-key-env or --new-key-prompt is required")
+                    }
+                };
+                let id = commands::key::add(&database_path, passphrase, &new_passphrase, label, Kdf::default())?;
+                println!("Added key slot {id}.");
+            }
+            KeyCommands::Remove {
+                database_path,
+                slot,
+                key_group,
+            } => {
+                let passphrase = key_group.read_passphrase()?.context("A key is required")?;
+                commands::key::remove(&database_path, &passphrase, slot)?;
+                println!("Removed key slot {slot}.");
+            }
+            KeyCommands::List { database_path } => {
+                for slot in commands::key::list(&database_path)? {
+                    println!(
+                        "{:>3} {} {} {}",
+                        slot.id,
+                        time::TimeSpec::new(slot.created_at, 0),
+                        slot.kdf,
+                        slot.label.unwrap_or_default()
+                    );
+                }
+            }
+        },
+        Commands::TrainDict {
+            database_path,
+            samples,
+            max_size,
+            key_group,
+        } => {
+            let key = key_group.read_key(&database_path)?;
+            let mut db = DatabaseOps::open(&database_path, key).context("open db")?;
+            let (id, len) = commands::train_dict::train_dict(&mut db, samples, max_size as usize)?;
+            println!(
+                "Trained dictionary {} of {} bytes, mount with --compress zstd-dict to use it.",
+                id, len
+            );
+        }
     };
 
     Ok(())
@@ -0,0 +1,2 @@
+ALTER TABLE inode ADD COLUMN inline_data BLOB; -- NULL when the file contents are stored in the block table
+ALTER TABLE inode ADD COLUMN inline_compression INTEGER; -- Same values as block.compression
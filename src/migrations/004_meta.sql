@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value NOT NULL
+);
+
+-- Databases created before the block size became configurable use 128 KiB blocks.
+INSERT INTO meta (key, value) VALUES ('block_size', 131072);
@@ -0,0 +1,9 @@
+-- Random version 4 UUID identifying the filesystem.
+INSERT INTO meta (key, value) VALUES ('uuid', lower(
+    hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-' ||
+    substr('89ab', 1 + (abs(random()) % 4), 1) || substr(hex(randomblob(2)), 2) || '-' || hex(randomblob(6))
+));
+
+-- Format features in use. A nightshift version that does not know one of these refuses to open the database.
+INSERT INTO meta (key, value) VALUES ('feature.inline_data', 1);
+INSERT INTO meta (key, value) VALUES ('feature.block_size', 1);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS dictionary (
+    id INTEGER PRIMARY KEY,
+    data BLOB NOT NULL,
+    created_secs INTEGER NOT NULL
+);
+
+ALTER TABLE block ADD COLUMN dict_id INTEGER REFERENCES dictionary(id) ON DELETE RESTRICT; -- Set when compression is 3 (ZstdDict)
+ALTER TABLE inode ADD COLUMN inline_dict_id INTEGER REFERENCES dictionary(id) ON DELETE RESTRICT;
@@ -0,0 +1 @@
+ALTER TABLE inode ADD COLUMN compression TEXT; -- Compression spec used for new blocks, NULL for the database default. Inherited from the parent directory
@@ -0,0 +1,5 @@
+ALTER TABLE block ADD COLUMN checksum INTEGER; -- xxh3 of the uncompressed data, NULL for blocks written before checksums
+ALTER TABLE inode ADD COLUMN inline_checksum INTEGER;
+
+-- Older versions would rewrite blocks without updating their checksum.
+INSERT INTO meta (key, value) VALUES ('feature.block_checksum', 1);
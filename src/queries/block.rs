@@ -1,24 +1,45 @@
-use std::cmp;
+use std::{
+    cmp,
+    io::{Read, Write},
+    sync::Arc,
+};
 
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+use crate::queries::dictionary::{self, Dictionary};
 use rusqlite::params;
 
-pub const BLOCK_SIZE: u64 = 128 * 1024;
+/// Block size of databases created without an explicit `--block-size`, and of every database created before the
+/// block size became configurable.
+pub const DEFAULT_BLOCK_SIZE: u64 = 128 * 1024;
+pub const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+pub const MAX_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
 
-pub fn get_block(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<Block> {
-    let mut stmt = tx.prepare_cached("SELECT bno, data, compression FROM block WHERE ino = ? AND bno = ?")?;
+pub fn validate_block_size(block_size: u64) -> Result<()> {
+    if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(())
+}
+
+pub fn get_block(tx: &mut rusqlite::Transaction, ino: u64, bno: u64, block_size: u64) -> Result<Block> {
+    let mut stmt =
+        tx.prepare_cached("SELECT bno, data, compression, dict_id, checksum FROM block WHERE ino = ? AND bno = ?")?;
     let mut rows = stmt.query(params![ino, bno])?;
     match rows.next()? {
         Some(row) => {
             let data = row.get_ref(1)?.as_blob()?;
             let compression: Option<u8> = row.get(2)?;
+            let mut cache = None;
             let block = CompressedBlock {
                 ino,
                 bno,
+                block_size,
                 compression: compression.try_into()?,
+                dictionary: dictionary::get_cached(tx, &mut cache, row.get(3)?)?,
+                checksum: row.get(4)?,
                 data,
             };
-            Ok(block.decompress())
+            block.decompress()
         }
         None => Err(rusqlite::Error::QueryReturnedNoRows.into()),
     }
@@ -28,22 +49,28 @@ pub fn iter_blocks_from(
     tx: &mut rusqlite::Transaction,
     ino: u64,
     offset: u64,
+    block_size: u64,
     mut iter: impl FnMut(Block) -> Result<bool>,
 ) -> Result<()> {
-    let bno = Block::offset_to_bno(offset);
-    let mut stmt =
-        tx.prepare_cached("SELECT bno, data, compression FROM block WHERE ino = ? AND bno >= ? ORDER BY bno")?;
+    let bno = Block::offset_to_bno(offset, block_size);
+    let mut stmt = tx.prepare_cached(
+        "SELECT bno, data, compression, dict_id, checksum FROM block WHERE ino = ? AND bno >= ? ORDER BY bno",
+    )?;
     let mut rows = stmt.query(params![ino, bno])?;
+    let mut cache = None;
     while let Some(row) = rows.next()? {
         let data = row.get_ref(1)?.as_blob()?;
         let compression: Option<u8> = row.get(2)?;
         let block = CompressedBlock {
             ino,
             bno: row.get(0)?,
+            block_size,
             compression: compression.try_into()?,
+            dictionary: dictionary::get_cached(tx, &mut cache, row.get(3)?)?,
+            checksum: row.get(4)?,
             data,
         };
-        let more = iter(block.decompress())?;
+        let more = iter(block.decompress()?)?;
         if !more {
             break;
         }
@@ -51,14 +78,24 @@ pub fn iter_blocks_from(
     Ok(())
 }
 
-pub fn update(tx: &mut rusqlite::Transaction, block: &Block, compression: Compression) -> Result<()> {
+/// Rewrites an existing block and returns the size of its stored data.
+pub fn update(tx: &mut rusqlite::Transaction, block: &Block, compressor: &mut Compressor) -> Result<u64> {
     let mut buf = Vec::new();
-    let cb = CompressedBlock::compress(block, compression, &mut buf);
+    let cb = CompressedBlock::compress(block, compressor, &mut buf);
 
-    let mut stmt = tx.prepare_cached("UPDATE block SET data = ?, compression = ? WHERE ino = ? AND bno = ?")?;
-    stmt.execute(params![cb.data, cb.compression as u8, block.ino, block.bno])?;
+    let mut stmt = tx.prepare_cached(
+        "UPDATE block SET data = ?, compression = ?, dict_id = ?, checksum = ? WHERE ino = ? AND bno = ?",
+    )?;
+    stmt.execute(params![
+        cb.data,
+        cb.compression as u8,
+        cb.dict_id(),
+        cb.checksum,
+        block.ino,
+        block.bno
+    ])?;
 
-    Ok(())
+    Ok(cb.data.len() as u64)
 }
 
 pub fn create(
@@ -66,16 +103,26 @@ pub fn create(
     ino: u64,
     offset: u64,
     data: &[u8],
-    compression: Compression,
+    block_size: u64,
+    compressor: &mut Compressor,
 ) -> Result<u64> {
-    let bno = Block::offset_to_bno(offset);
-    let mut block = Block::empty(ino, bno);
+    let bno = Block::offset_to_bno(offset, block_size);
+    let mut block = Block::empty(ino, bno, block_size);
     let written = block.consume(data);
     let mut buf = Vec::new();
-    let cb = CompressedBlock::compress(&block, compression, &mut buf);
+    let cb = CompressedBlock::compress(&block, compressor, &mut buf);
 
-    let mut stmt = tx.prepare_cached("INSERT INTO block (ino, bno, data, compression) VALUES (?, ?, ?, ?)")?;
-    stmt.execute(params![block.ino, block.bno, cb.data, compression as u8])?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO block (ino, bno, data, compression, dict_id, checksum) VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    stmt.execute(params![
+        block.ino,
+        block.bno,
+        cb.data,
+        cb.compression as u8,
+        cb.dict_id(),
+        cb.checksum
+    ])?;
 
     Ok(written)
 }
@@ -86,6 +133,85 @@ pub fn remove_blocks_from(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) ->
     Ok(())
 }
 
+pub fn list_inos(tx: &mut rusqlite::Transaction) -> Result<Vec<u64>> {
+    let mut stmt = tx.prepare_cached("SELECT DISTINCT ino FROM block ORDER BY ino")?;
+    let inos = stmt
+        .query_map(params![], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    Ok(inos)
+}
+
+pub fn list_bnos(tx: &mut rusqlite::Transaction, ino: u64) -> Result<Vec<u64>> {
+    let mut stmt = tx.prepare_cached("SELECT bno FROM block WHERE ino = ? AND bno >= 0 ORDER BY bno")?;
+    let bnos = stmt
+        .query_map(params![ino], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    Ok(bnos)
+}
+
+/// Returns up to `limit` `(ino, bno, stored size)` of the blocks following `(ino, bno)`, in order.
+pub fn list_after(tx: &mut rusqlite::Transaction, ino: u64, bno: u64, limit: usize) -> Result<Vec<(u64, u64, u64)>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT ino, bno, length(data) FROM block WHERE (ino, bno) > (?, ?) AND bno >= 0 ORDER BY ino, bno LIMIT ?",
+    )?;
+    let blocks = stmt
+        .query_map(params![ino, bno, limit], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(blocks)
+}
+
+pub fn count_after(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT count(*) FROM block WHERE (ino, bno) > (?, ?) AND bno >= 0")?;
+    let count = stmt.query_row(params![ino, bno], |row| row.get(0))?;
+    Ok(count)
+}
+
+/// Returns up to `limit` randomly chosen `(ino, bno)` pairs.
+pub fn sample(tx: &mut rusqlite::Transaction, limit: usize) -> Result<Vec<(u64, u64)>> {
+    let mut stmt = tx.prepare_cached("SELECT ino, bno FROM block WHERE bno >= 0 ORDER BY random() LIMIT ?")?;
+    let blocks = stmt
+        .query_map(params![limit], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(blocks)
+}
+
+/// Inserts a block under a negative block number so that it does not collide with the existing blocks of a file
+/// that is being rewritten. The staged blocks replace the existing ones once `commit_staged` is called.
+pub fn create_staged(tx: &mut rusqlite::Transaction, block: &Block, compressor: &mut Compressor) -> Result<()> {
+    let mut buf = Vec::new();
+    let cb = CompressedBlock::compress(block, compressor, &mut buf);
+
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO block (ino, bno, data, compression, dict_id, checksum) VALUES (?, -1 - ?, ?, ?, ?, ?)",
+    )?;
+    stmt.execute(params![
+        block.ino,
+        block.bno,
+        cb.data,
+        cb.compression as u8,
+        cb.dict_id(),
+        cb.checksum
+    ])?;
+    Ok(())
+}
+
+/// Deletes the staged blocks of every file, left by an interrupted repack.
+pub fn discard_staged(tx: &mut rusqlite::Transaction) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM block WHERE bno < 0")?;
+    stmt.execute(params![])?;
+    Ok(())
+}
+
+pub fn commit_staged(tx: &mut rusqlite::Transaction, ino: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM block WHERE ino = ? AND bno >= 0")?;
+    stmt.execute(params![ino])?;
+    let mut stmt = tx.prepare_cached("UPDATE block SET bno = -1 - bno WHERE ino = ? AND bno < 0")?;
+    stmt.execute(params![ino])?;
+    Ok(())
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Default, clap::ValueEnum)]
 #[repr(u8)]
 pub enum Compression {
@@ -93,6 +219,15 @@ pub enum Compression {
     #[default]
     LZ4 = 1,
     Zstd = 2,
+    /// Zstd using the trained dictionary of the database.
+    ZstdDict = 3,
+    /// Xz (LZMA2) at the default preset. Slow, meant for archives that are rarely written.
+    Xz = 4,
+    /// Brotli at the highest quality. Slow, meant for archives that are rarely written.
+    Brotli = 5,
+    /// Picks LZ4 or zstd for each file from the entropy of its first block. Never stored, blocks record the
+    /// compression that was picked.
+    Auto = 255,
 }
 
 impl TryFrom<Option<u8>> for Compression {
@@ -103,6 +238,9 @@ impl TryFrom<Option<u8>> for Compression {
             None | Some(1) => Ok(Compression::LZ4),
             Some(0) => Ok(Compression::None),
             Some(2) => Ok(Compression::Zstd),
+            Some(3) => Ok(Compression::ZstdDict),
+            Some(4) => Ok(Compression::Xz),
+            Some(5) => Ok(Compression::Brotli),
             _ => Err(crate::errors::Error::InvalidCompression),
         }
     }
@@ -113,42 +251,323 @@ pub struct CompressedBlock<'d> {
     pub ino: u64,
     /// Block number.
     pub bno: u64,
+    /// Size of a full block.
+    pub block_size: u64,
     /// Compression scheme
     pub compression: Compression,
+    /// Dictionary used by `Compression::ZstdDict`.
+    pub dictionary: Option<&'d Dictionary>,
+    /// xxh3 of the uncompressed data, stored as a signed integer. `None` for blocks written before checksums.
+    pub checksum: Option<i64>,
     // Block data. Always compressed
     pub data: &'d [u8],
 }
 
+/// Compression algorithm and its settings, written as `none`, `lz4`, `zstd`, `zstd:<level>` or `zstd:<level>:long`.
+/// `zstd-dict` and `auto` accept the same settings as `zstd`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct CompressionSpec {
+    pub compression: Compression,
+    /// Zstd compression level, 0 selects the zstd default.
+    pub level: i32,
+    /// Enables zstd long distance matching.
+    pub long: bool,
+}
+
+impl From<Compression> for CompressionSpec {
+    fn from(compression: Compression) -> Self {
+        CompressionSpec {
+            compression,
+            level: 0,
+            long: false,
+        }
+    }
+}
+
+impl std::str::FromStr for CompressionSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = value.split(':');
+        let name = parts.next().unwrap_or_default();
+        let mut spec = CompressionSpec::from(<Compression as clap::ValueEnum>::from_str(name, true)?);
+        if let Some(level) = parts.next() {
+            if !spec.compression.has_zstd_settings() {
+                return Err(format!("{name} does not support compression levels"));
+            }
+            spec.level = level.parse().map_err(|e| format!("invalid level {level:?}: {e}"))?;
+            if !zstd::compression_level_range().contains(&spec.level) {
+                return Err(format!(
+                    "zstd level must be within {:?}",
+                    zstd::compression_level_range()
+                ));
+            }
+        }
+        match parts.next() {
+            Some("long") => spec.long = true,
+            Some(flag) => return Err(format!("unknown compression flag {flag:?}")),
+            None => {}
+        }
+        if parts.next().is_some() {
+            return Err(format!("invalid compression {value:?}"));
+        }
+        Ok(spec)
+    }
+}
+
+impl std::fmt::Display for CompressionSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = clap::ValueEnum::to_possible_value(&self.compression).expect("no skipped variants");
+        write!(f, "{}", name.get_name())?;
+        if self.compression.has_zstd_settings() && (self.level != 0 || self.long) {
+            write!(f, ":{}", self.level)?;
+            if self.long {
+                write!(f, ":long")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Xz preset used by `Compression::Xz`, the default of the xz tool.
+const XZ_PRESET: u32 = 6;
+
+/// Percentage of its size that compression must save for a block to be stored compressed, when none is configured.
+pub const DEFAULT_MIN_SAVINGS: u8 = 0;
+
+/// Entropy, in bits per byte, below which `Compression::Auto` picks zstd. Text, logs and source code sit well below
+/// it and compress much better with zstd, media and archives sit above it where LZ4 is nearly as good and faster.
+const AUTO_ZSTD_MAX_ENTROPY: f64 = 6.0;
+
+/// Compresses blocks according to a `CompressionSpec`. The zstd context is created on first use and reused for
+/// the following blocks.
+pub struct Compressor {
+    spec: CompressionSpec,
+    min_savings: u8,
+    dictionary: Option<Arc<Dictionary>>,
+    /// Compression picked by `Compression::Auto` for the file being written.
+    auto: Option<Compression>,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl Compressor {
+    pub fn new(spec: CompressionSpec) -> Self {
+        Compressor {
+            spec,
+            min_savings: DEFAULT_MIN_SAVINGS,
+            dictionary: None,
+            auto: None,
+            zstd: None,
+        }
+    }
+
+    /// Blocks whose compressed size is not at least `min_savings` percent smaller are stored uncompressed.
+    pub fn with_min_savings(mut self, min_savings: u8) -> Self {
+        self.min_savings = min_savings;
+        self
+    }
+
+    /// Sets the dictionary used by `Compression::ZstdDict`. Without one, blocks are compressed with plain zstd.
+    pub fn with_dictionary(mut self, dictionary: Option<Arc<Dictionary>>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Forgets the compression picked by `Compression::Auto`, before compressing the blocks of another file with the
+    /// same compressor.
+    pub fn start_file(&mut self) {
+        self.auto = None;
+    }
+
+    fn compression(&mut self, data: &[u8]) -> Compression {
+        match (self.spec.compression, &self.dictionary) {
+            (Compression::ZstdDict, None) => Compression::Zstd,
+            (Compression::Auto, _) => *self.auto.get_or_insert_with(|| {
+                let entropy = entropy(data);
+                let compression = if entropy < AUTO_ZSTD_MAX_ENTROPY {
+                    Compression::Zstd
+                } else {
+                    Compression::LZ4
+                };
+                log::debug!("Auto compression picked {:?} for entropy {:.2}", compression, entropy);
+                compression
+            }),
+            (compression, _) => compression,
+        }
+    }
+
+    /// Returns true when `compressed` bytes save enough over `len` bytes to be worth decompressing.
+    fn saves_enough(&self, len: usize, compressed: usize) -> bool {
+        compressed < len && compressed * 100 <= len * (100 - self.min_savings.min(100) as usize)
+    }
+
+    fn zstd(&mut self) -> &mut zstd::bulk::Compressor<'static> {
+        let spec = self.spec;
+        let dictionary = self
+            .dictionary
+            .as_ref()
+            .filter(|_| spec.compression == Compression::ZstdDict);
+        self.zstd.get_or_insert_with(|| {
+            let mut zstd = match dictionary {
+                Some(dictionary) => zstd::bulk::Compressor::with_dictionary(spec.level, &dictionary.data),
+                None => zstd::bulk::Compressor::new(spec.level),
+            }
+            .expect("unable to create zstd context");
+            zstd.long_distance_matching(spec.long)
+                .expect("unable to enable long distance matching");
+            zstd
+        })
+    }
+}
+
+impl std::fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compressor").field("spec", &self.spec).finish()
+    }
+}
+
+/// Checksum of uncompressed block data, as stored in the database.
+pub fn checksum(data: &[u8]) -> i64 {
+    xxhash_rust::xxh3::xxh3_64(data) as i64
+}
+
+/// Shannon entropy of `data` in bits per byte, from 0 for constant data to 8 for random data.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+impl Compression {
+    pub fn is_zstd(self) -> bool {
+        matches!(self, Compression::Zstd | Compression::ZstdDict)
+    }
+
+    /// Returns the format feature that must be enabled before blocks using this compression are written, for
+    /// compressions that older versions of nightshift cannot read.
+    pub fn feature(self) -> Option<&'static str> {
+        match self {
+            Compression::Xz => Some("xz"),
+            Compression::Brotli => Some("brotli"),
+            _ => None,
+        }
+    }
+
+    /// Returns true when the compression accepts a zstd level and long distance matching.
+    pub fn has_zstd_settings(self) -> bool {
+        self.is_zstd() || self == Compression::Auto
+    }
+}
+
 impl<'d> CompressedBlock<'d> {
-    pub fn decompress(self) -> Block {
+    pub fn dict_id(&self) -> Option<u64> {
+        self.dictionary.map(|d| d.id)
+    }
+
+    /// Decompresses the block. Corrupted data is logged with the block position and reported as
+    /// `Error::Corrupted` so that a single bad block does not take the whole filesystem down.
+    pub fn decompress(self) -> Result<Block> {
+        match self.try_decompress() {
+            Ok(buf) => Ok(Block {
+                ino: self.ino,
+                bno: self.bno,
+                block_size: self.block_size,
+                data: buf,
+            }),
+            Err(e) => {
+                log::error!(
+                    "Corrupted block (ino={}, bno={}, compression={:?}): {}",
+                    self.ino,
+                    self.bno,
+                    self.compression,
+                    e
+                );
+                Err(Error::Corrupted)
+            }
+        }
+    }
+
+    fn try_decompress(&self) -> std::result::Result<Vec<u8>, String> {
+        // Decoders that write into a growing buffer are limited to one byte more than a block, so that corrupted
+        // data cannot make them allocate without bounds.
+        let limit = self.block_size + 1;
         let buf = match self.compression {
             Compression::None => self.data.to_owned(),
+            Compression::Auto => return Err("auto compression is never stored".to_owned()),
             Compression::LZ4 => {
-                let mut buf = vec![0u8; BLOCK_SIZE as usize];
-                let n = lz4_flex::decompress_into(self.data, &mut buf).expect("lz4 decompress output too small");
+                let mut buf = vec![0u8; self.block_size as usize];
+                let n = lz4_flex::decompress_into(self.data, &mut buf).map_err(|e| format!("lz4: {e}"))?;
                 log::debug!("LZ4 decompress {} result {}", self.data.len(), n);
                 buf.truncate(n);
                 buf
             }
             Compression::Zstd => {
-                let mut buf = Vec::with_capacity(BLOCK_SIZE as usize);
-                zstd::stream::copy_decode(self.data, &mut buf).expect("zstd decompress error");
+                let mut buf = Vec::with_capacity(self.block_size as usize);
+                zstd::stream::read::Decoder::with_buffer(self.data)
+                    .and_then(|d| d.take(limit).read_to_end(&mut buf))
+                    .map_err(|e| format!("zstd: {e}"))?;
+                log::debug!("Zstd decompress {} result {}", self.data.len(), buf.len());
+                buf
+            }
+            Compression::ZstdDict => {
+                let dictionary = self.dictionary.ok_or("zstd dictionary missing")?;
+                let buf = zstd::bulk::Decompressor::with_dictionary(&dictionary.data)
+                    .and_then(|mut d| d.decompress(self.data, self.block_size as usize))
+                    .map_err(|e| format!("zstd: {e}"))?;
                 log::debug!("Zstd decompress {} result {}", self.data.len(), buf.len());
                 buf
             }
+            Compression::Xz => {
+                let mut buf = Vec::with_capacity(self.block_size as usize);
+                xz2::read::XzDecoder::new(self.data)
+                    .take(limit)
+                    .read_to_end(&mut buf)
+                    .map_err(|e| format!("xz: {e}"))?;
+                log::debug!("Xz decompress {} result {}", self.data.len(), buf.len());
+                buf
+            }
+            Compression::Brotli => {
+                let mut buf = Vec::with_capacity(self.block_size as usize);
+                brotli::Decompressor::new(self.data, 4096)
+                    .take(limit)
+                    .read_to_end(&mut buf)
+                    .map_err(|e| format!("brotli: {e}"))?;
+                log::debug!("Brotli decompress {} result {}", self.data.len(), buf.len());
+                buf
+            }
         };
-        Block {
-            ino: self.ino,
-            bno: self.bno,
-            data: buf,
+        if buf.len() as u64 > self.block_size {
+            return Err(format!(
+                "decompressed data exceeds the block size of {}",
+                self.block_size
+            ));
         }
+        if let Some(expected) = self.checksum {
+            let actual = checksum(&buf);
+            if actual != expected {
+                return Err(format!("checksum mismatch, expected {expected:#x} got {actual:#x}"));
+            }
+        }
+        Ok(buf)
     }
 
-    pub fn compress(block: &Block, compression: Compression, scratch: &'d mut Vec<u8>) -> CompressedBlock<'d> {
+    pub fn compress(block: &Block, compressor: &'d mut Compressor, scratch: &'d mut Vec<u8>) -> CompressedBlock<'d> {
         scratch.clear();
 
+        let mut compression = compressor.compression(&block.data);
         match compression {
             Compression::None => scratch.extend_from_slice(&block.data),
+            Compression::Auto => unreachable!("auto compression is resolved by the compressor"),
             Compression::LZ4 => {
                 let max_size = lz4_flex::block::get_maximum_output_size(block.data.len());
                 scratch.resize(max_size, 0);
@@ -156,16 +575,46 @@ impl<'d> CompressedBlock<'d> {
                 log::debug!("LZ4 compress {} result {}", block.data.len(), written);
                 scratch.truncate(written);
             }
-            Compression::Zstd => {
-                zstd::stream::copy_encode(&block.data[..], &mut *scratch, 0).expect("");
+            Compression::Zstd | Compression::ZstdDict => {
+                scratch.reserve(zstd::zstd_safe::compress_bound(block.data.len()));
+                compressor
+                    .zstd()
+                    .compress_to_buffer(&block.data[..], scratch)
+                    .expect("zstd compress error");
                 log::debug!("Zstd compress {} result {}", block.data.len(), scratch.len());
             }
+            Compression::Xz => {
+                let stream = xz2::stream::Stream::new_easy_encoder(XZ_PRESET, xz2::stream::Check::None)
+                    .expect("unable to create xz stream");
+                let mut encoder = xz2::write::XzEncoder::new_stream(&mut *scratch, stream);
+                encoder.write_all(&block.data).expect("xz compress error");
+                encoder.finish().expect("xz compress error");
+                log::debug!("Xz compress {} result {}", block.data.len(), scratch.len());
+            }
+            Compression::Brotli => {
+                let params = brotli::enc::BrotliEncoderParams::default();
+                brotli::BrotliCompress(&mut &block.data[..], &mut *scratch, &params).expect("brotli compress error");
+                log::debug!("Brotli compress {} result {}", block.data.len(), scratch.len());
+            }
+        }
+
+        if compression != Compression::None && !compressor.saves_enough(block.data.len(), scratch.len()) {
+            log::debug!("Storing block uncompressed, {:?} saved too little", compression);
+            compression = Compression::None;
+            scratch.clear();
+            scratch.extend_from_slice(&block.data);
         }
 
         CompressedBlock {
             ino: block.ino,
             bno: block.bno,
+            block_size: block.block_size,
             compression,
+            dictionary: match compression {
+                Compression::ZstdDict => compressor.dictionary.as_deref(),
+                _ => None,
+            },
+            checksum: Some(checksum(&block.data)),
             data: &scratch[..],
         }
     }
@@ -177,6 +626,7 @@ impl std::fmt::Debug for CompressedBlock<'_> {
             .field("ino", &self.ino)
             .field("bno", &self.bno)
             .field("compression", &self.compression)
+            .field("dict_id", &self.dict_id())
             .field("data.len()", &self.data.len())
             .finish()
     }
@@ -187,33 +637,36 @@ pub struct Block {
     pub ino: u64,
     /// Block number.
     pub bno: u64,
+    /// Size of a full block.
+    pub block_size: u64,
     /// Block data. Always uncompressed.
     pub data: Vec<u8>,
 }
 
 impl Block {
-    pub fn empty(ino: u64, bno: u64) -> Block {
+    pub fn empty(ino: u64, bno: u64, block_size: u64) -> Block {
         Block {
             ino,
             bno,
+            block_size,
             data: Vec::new(),
         }
     }
 
-    pub fn offset_to_bno(offset: u64) -> u64 {
-        offset / BLOCK_SIZE
+    pub fn offset_to_bno(offset: u64, block_size: u64) -> u64 {
+        offset / block_size
     }
 
     pub fn start_offset(&self) -> u64 {
-        self.bno * BLOCK_SIZE
+        self.bno * self.block_size
     }
 
     pub fn end_offset(&self) -> u64 {
-        (self.bno + 1) * BLOCK_SIZE
+        (self.bno + 1) * self.block_size
     }
 
     fn available(&self) -> u32 {
-        u32::try_from(BLOCK_SIZE - self.data.len() as u64).expect("block size overflow")
+        u32::try_from(self.block_size - self.data.len() as u64).expect("block size overflow")
     }
 
     pub fn consume(&mut self, data: &[u8]) -> u64 {
@@ -261,47 +714,55 @@ impl std::fmt::Debug for Block {
 
 #[cfg(test)]
 mod tests {
+    use std::io::Read;
+
     use rand::RngCore;
     use test_log::test;
 
+    use crate::errors::Error;
     use crate::queries::block::CompressedBlock;
     use crate::queries::block::Compression;
+    use crate::queries::block::CompressionSpec;
+    use crate::queries::block::Compressor;
 
     use super::Block;
-    use super::BLOCK_SIZE;
+    use super::{
+        checksum, entropy, validate_block_size, AUTO_ZSTD_MAX_ENTROPY, DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE,
+        MIN_BLOCK_SIZE,
+    };
 
     #[test]
     fn test_block() {
-        let b = Block::empty(37, 1);
+        let b = Block::empty(37, 1, DEFAULT_BLOCK_SIZE);
         assert_eq!(b.ino, 37);
-        assert_eq!(b.start_offset(), BLOCK_SIZE);
-        assert_eq!(b.end_offset(), BLOCK_SIZE + BLOCK_SIZE);
-        assert_eq!(b.available(), BLOCK_SIZE as u32);
+        assert_eq!(b.start_offset(), DEFAULT_BLOCK_SIZE);
+        assert_eq!(b.end_offset(), DEFAULT_BLOCK_SIZE + DEFAULT_BLOCK_SIZE);
+        assert_eq!(b.available(), DEFAULT_BLOCK_SIZE as u32);
     }
 
     #[test]
     fn test_block_consume() {
-        let mut b = Block::empty(37, 0);
+        let mut b = Block::empty(37, 0, DEFAULT_BLOCK_SIZE);
         assert_eq!(b.consume(&[0; 100]), 100);
-        assert_eq!(b.consume(&[1; BLOCK_SIZE as usize]), BLOCK_SIZE - 100);
+        assert_eq!(b.consume(&[1; DEFAULT_BLOCK_SIZE as usize]), DEFAULT_BLOCK_SIZE - 100);
         assert!(b.data[..100].iter().all(|&b| b == 0));
         assert!(b.data[100..].iter().all(|&b| b == 1));
     }
 
     #[test]
     fn test_block_write_at() {
-        let mut b = Block::empty(0, 1);
-        assert_eq!(b.write_at(BLOCK_SIZE, &[1; 5]), (5, 5));
+        let mut b = Block::empty(0, 1, DEFAULT_BLOCK_SIZE);
+        assert_eq!(b.write_at(DEFAULT_BLOCK_SIZE, &[1; 5]), (5, 5));
         assert_eq!(b.data, vec![1; 5]);
 
-        let mut b = Block::empty(0, 1);
-        assert_eq!(b.write_at(BLOCK_SIZE + 5, &[1; 5]), (5, 10));
+        let mut b = Block::empty(0, 1, DEFAULT_BLOCK_SIZE);
+        assert_eq!(b.write_at(DEFAULT_BLOCK_SIZE + 5, &[1; 5]), (5, 10));
         assert_eq!(b.data, vec![0, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
     }
 
     #[test]
     fn test_block_copy_into() {
-        let mut b = Block::empty(0, 0);
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
         b.data = (1u8..=10).collect();
 
         let mut buf = Vec::with_capacity(5);
@@ -317,23 +778,34 @@ mod tests {
 
     #[test]
     fn test_block_offset_to_bno() {
-        assert_eq!(Block::offset_to_bno(0), 0);
-        assert_eq!(Block::offset_to_bno(BLOCK_SIZE), 1);
+        assert_eq!(Block::offset_to_bno(0, DEFAULT_BLOCK_SIZE), 0);
+        assert_eq!(Block::offset_to_bno(DEFAULT_BLOCK_SIZE, DEFAULT_BLOCK_SIZE), 1);
+    }
+
+    #[test]
+    fn test_validate_block_size() {
+        assert!(validate_block_size(DEFAULT_BLOCK_SIZE).is_ok());
+        assert!(validate_block_size(MIN_BLOCK_SIZE).is_ok());
+        assert!(validate_block_size(MAX_BLOCK_SIZE).is_ok());
+        assert!(validate_block_size(MIN_BLOCK_SIZE / 2).is_err());
+        assert!(validate_block_size(MAX_BLOCK_SIZE * 2).is_err());
+        assert!(validate_block_size(100_000).is_err());
     }
 
     #[test]
     fn test_none_compression() {
         let mut rng = rand::thread_rng();
 
-        let mut b = Block::empty(0, 0);
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
         b.data = vec![0; 1000];
         rng.fill_bytes(&mut b.data);
 
+        let mut compressor = Compressor::new(Compression::None.into());
         let mut sratch = Vec::new();
-        let compressed_block: CompressedBlock<'_> = CompressedBlock::compress(&b, Compression::None, &mut sratch);
+        let compressed_block: CompressedBlock<'_> = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
         assert_eq!(b.data, compressed_block.data);
 
-        let decompressed_block = compressed_block.decompress();
+        let decompressed_block = compressed_block.decompress().unwrap();
         assert_eq!(b.data, decompressed_block.data);
     }
 
@@ -341,22 +813,21 @@ mod tests {
     fn test_lz4_compression() {
         let mut rng = rand::thread_rng();
 
-        let mut b = Block::empty(0, 0);
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
         b.data = vec![0; 1000];
         rng.fill_bytes(&mut b.data);
+        // Keep the data compressible so it is not stored uncompressed.
+        b.data.iter_mut().for_each(|b| *b %= 4);
 
+        let mut compressor = Compressor::new(Compression::LZ4.into());
         let mut sratch = Vec::new();
         let compressed = lz4_flex::compress(&b.data);
-        let compressed_block: CompressedBlock<'_> = CompressedBlock::compress(&b, Compression::LZ4, &mut sratch);
+        let compressed_block: CompressedBlock<'_> = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
 
         assert_eq!(compressed, compressed_block.data);
 
-        let decompressed_block = compressed_block.decompress();
-        let decompressed = lz4_flex::decompress(
-            &compressed[..],
-            lz4_flex::block::get_maximum_output_size(compressed.len()),
-        )
-        .unwrap();
+        let decompressed_block = compressed_block.decompress().unwrap();
+        let decompressed = lz4_flex::decompress(&compressed[..], b.data.len()).unwrap();
         assert_eq!(decompressed, decompressed_block.data);
         assert_eq!(b.data, decompressed_block.data);
     }
@@ -365,19 +836,251 @@ mod tests {
     fn test_zstd_compression() {
         let mut rng = rand::thread_rng();
 
-        let mut b = Block::empty(0, 0);
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
         b.data = vec![0; 1000];
         rng.fill_bytes(&mut b.data);
+        // Keep the data compressible so it is not stored uncompressed.
+        b.data.iter_mut().for_each(|b| *b %= 4);
 
+        let mut compressor = Compressor::new(Compression::Zstd.into());
         let mut sratch = Vec::new();
-        let compressed = zstd::encode_all(&b.data[..], 0).unwrap();
-        let compressed_block = CompressedBlock::compress(&b, Compression::Zstd, &mut sratch);
+        let compressed = zstd::bulk::compress(&b.data[..], 0).unwrap();
+        let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
 
         assert_eq!(compressed, compressed_block.data);
 
-        let decompressed_block = compressed_block.decompress();
+        let decompressed_block = compressed_block.decompress().unwrap();
         let decompressed = zstd::decode_all(&compressed[..]).unwrap();
         assert_eq!(decompressed, decompressed_block.data);
         assert_eq!(b.data, decompressed_block.data);
     }
+
+    #[test]
+    fn test_xz_compression() {
+        let mut rng = rand::thread_rng();
+
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        b.data = vec![0; 1000];
+        rng.fill_bytes(&mut b.data);
+        b.data.iter_mut().for_each(|b| *b %= 4);
+
+        let mut compressor = Compressor::new(Compression::Xz.into());
+        let mut sratch = Vec::new();
+        let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::Xz);
+        assert!(compressed_block.data.len() < b.data.len());
+
+        let mut decompressed = Vec::new();
+        xz2::read::XzDecoder::new(compressed_block.data)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(b.data, decompressed);
+        assert_eq!(b.data, compressed_block.decompress().unwrap().data);
+    }
+
+    #[test]
+    fn test_brotli_compression() {
+        let mut rng = rand::thread_rng();
+
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        b.data = vec![0; 1000];
+        rng.fill_bytes(&mut b.data);
+        b.data.iter_mut().for_each(|b| *b %= 4);
+
+        let mut compressor = Compressor::new(Compression::Brotli.into());
+        let mut sratch = Vec::new();
+        let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::Brotli);
+        assert!(compressed_block.data.len() < b.data.len());
+
+        let mut decompressed = Vec::new();
+        brotli::BrotliDecompress(&mut &compressed_block.data[..], &mut decompressed).unwrap();
+        assert_eq!(b.data, decompressed);
+        assert_eq!(b.data, compressed_block.decompress().unwrap().data);
+    }
+
+    #[test]
+    fn test_compression_ids() {
+        for compression in [
+            Compression::None,
+            Compression::LZ4,
+            Compression::Zstd,
+            Compression::ZstdDict,
+            Compression::Xz,
+            Compression::Brotli,
+        ] {
+            assert_eq!(Compression::try_from(Some(compression as u8)), Ok(compression));
+        }
+        assert!(Compression::try_from(Some(Compression::Auto as u8)).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        let garbage = [0xffu8; 64];
+        for compression in [
+            Compression::LZ4,
+            Compression::Zstd,
+            Compression::ZstdDict,
+            Compression::Xz,
+            Compression::Brotli,
+        ] {
+            let block = CompressedBlock {
+                ino: 1,
+                bno: 2,
+                block_size: MIN_BLOCK_SIZE,
+                compression,
+                dictionary: None,
+                checksum: None,
+                data: &garbage,
+            };
+            assert_eq!(block.decompress().unwrap_err(), Error::Corrupted);
+        }
+
+        // Valid data that decompresses to more than a block is corrupted too.
+        let data = zstd::bulk::compress(&vec![0u8; MIN_BLOCK_SIZE as usize * 2], 0).unwrap();
+        let block = CompressedBlock {
+            ino: 1,
+            bno: 2,
+            block_size: MIN_BLOCK_SIZE,
+            compression: Compression::Zstd,
+            dictionary: None,
+            checksum: None,
+            data: &data,
+        };
+        assert_eq!(block.decompress().unwrap_err(), Error::Corrupted);
+        assert_eq!(Error::Corrupted.errno(), libc::EIO);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut b = Block::empty(1, 2, DEFAULT_BLOCK_SIZE);
+        b.data = b"hello world".repeat(100);
+        let mut compressor = Compressor::new(Compression::LZ4.into());
+        let mut sratch = Vec::new();
+        let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.checksum, Some(checksum(&b.data)));
+
+        // Valid LZ4 data whose content does not match the checksum of the block.
+        let other = lz4_flex::compress(&b"hello there".repeat(100));
+        let block = CompressedBlock {
+            data: &other,
+            ..compressed_block
+        };
+        assert_eq!(block.decompress().unwrap_err(), Error::Corrupted);
+
+        // Blocks written before checksums are not verified.
+        let block = CompressedBlock {
+            ino: 1,
+            bno: 2,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compression: Compression::LZ4,
+            dictionary: None,
+            checksum: None,
+            data: &other,
+        };
+        assert!(block.decompress().is_ok());
+    }
+
+    #[test]
+    fn test_zstd_level_compression() {
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        b.data = (0..DEFAULT_BLOCK_SIZE).map(|i| (i % 7) as u8).collect();
+
+        let mut compressor = Compressor::new("zstd:-5:long".parse().unwrap());
+        for _ in 0..2 {
+            let mut sratch = Vec::new();
+            let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+            assert_eq!(compressed_block.compression, Compression::Zstd);
+            assert!(compressed_block.data.len() < b.data.len());
+            assert_eq!(b.data, compressed_block.decompress().unwrap().data);
+        }
+    }
+
+    #[test]
+    fn test_compression_spec() {
+        let spec: CompressionSpec = "zstd:19:long".parse().unwrap();
+        assert_eq!(
+            spec,
+            CompressionSpec {
+                compression: Compression::Zstd,
+                level: 19,
+                long: true
+            }
+        );
+        assert_eq!(spec.to_string(), "zstd:19:long");
+        assert_eq!("zstd:-3".parse::<CompressionSpec>().unwrap().level, -3);
+        assert_eq!("LZ4".parse::<CompressionSpec>().unwrap(), Compression::LZ4.into());
+        assert_eq!(CompressionSpec::from(Compression::Zstd).to_string(), "zstd");
+        assert!("zstd:23".parse::<CompressionSpec>().is_err());
+        assert!("lz4:3".parse::<CompressionSpec>().is_err());
+        assert!("zstd:3:fast".parse::<CompressionSpec>().is_err());
+        assert_eq!("brotli".parse::<CompressionSpec>().unwrap(), Compression::Brotli.into());
+        assert!("xz:6".parse::<CompressionSpec>().is_err());
+        assert!("lzo".parse::<CompressionSpec>().is_err());
+    }
+
+    #[test]
+    fn test_incompressible_fallback() {
+        let mut rng = rand::thread_rng();
+
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        b.data = vec![0; 1000];
+        rng.fill_bytes(&mut b.data);
+
+        for compression in [Compression::LZ4, Compression::Zstd] {
+            let mut compressor = Compressor::new(compression.into());
+            let mut sratch = Vec::new();
+            let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+            assert_eq!(compressed_block.compression, Compression::None);
+            assert_eq!(b.data, compressed_block.data);
+            assert_eq!(b.data, compressed_block.decompress().unwrap().data);
+        }
+    }
+
+    #[test]
+    fn test_min_savings() {
+        let mut b = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        // Half of the block is a repeated pattern, the other half does not compress.
+        b.data = (0..1000u32)
+            .map(|i| if i < 500 { 0 } else { (i * 7919 % 251) as u8 })
+            .collect();
+        let mut sratch = Vec::new();
+
+        let mut compressor = Compressor::new(Compression::Zstd.into()).with_min_savings(30);
+        let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::Zstd);
+
+        let mut compressor = Compressor::new(Compression::Zstd.into()).with_min_savings(90);
+        let compressed_block = CompressedBlock::compress(&b, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::None);
+        assert_eq!(b.data, compressed_block.decompress().unwrap().data);
+    }
+
+    #[test]
+    fn test_auto_compression() {
+        let mut rng = rand::thread_rng();
+        let mut sratch = Vec::new();
+
+        let mut text = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        text.data = b"2024-08-01 12:00:00 INFO request handled in 12ms\n".repeat(100);
+        assert!(entropy(&text.data) < AUTO_ZSTD_MAX_ENTROPY);
+        let mut compressor = Compressor::new("auto".parse().unwrap());
+        let compressed_block = CompressedBlock::compress(&text, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::Zstd);
+        assert_eq!(text.data, compressed_block.decompress().unwrap().data);
+
+        // Mostly random data with a compressible tail, LZ4 is picked from the first block and kept for the file.
+        let mut media = Block::empty(0, 0, DEFAULT_BLOCK_SIZE);
+        media.data = vec![0; 4096];
+        rng.fill_bytes(&mut media.data[..3072]);
+        assert!(entropy(&media.data) > AUTO_ZSTD_MAX_ENTROPY);
+        let mut compressor = Compressor::new("auto".parse().unwrap());
+        let compressed_block = CompressedBlock::compress(&media, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::LZ4);
+        let compressed_block = CompressedBlock::compress(&text, &mut compressor, &mut sratch);
+        assert_eq!(compressed_block.compression, Compression::LZ4);
+
+        assert_eq!(entropy(&[7; 100]), 0.0);
+        assert_eq!("auto:5".parse::<CompressionSpec>().unwrap().to_string(), "auto:5");
+    }
 }
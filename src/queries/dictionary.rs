@@ -0,0 +1,69 @@
+use std::time::SystemTime;
+
+use crate::{
+    errors::{Error, Result},
+    time::TimeSpec,
+};
+use rusqlite::{params, OptionalExtension};
+
+/// A trained zstd dictionary, referenced by the blocks compressed with `Compression::ZstdDict`.
+pub struct Dictionary {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
+impl std::fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &self.id)
+            .field("data.len()", &self.data.len())
+            .finish()
+    }
+}
+
+/// Takes a shared transaction so dictionaries can be loaded while iterating over blocks.
+pub fn get(tx: &rusqlite::Transaction, id: u64) -> Result<Dictionary> {
+    let mut stmt = tx.prepare_cached("SELECT data FROM dictionary WHERE id = ?")?;
+    let data = stmt.query_row(params![id], |row| row.get(0))?;
+    Ok(Dictionary { id, data })
+}
+
+/// Returns the most recently trained dictionary, which is used to compress new blocks.
+pub fn latest(tx: &mut rusqlite::Transaction) -> Result<Option<Dictionary>> {
+    let mut stmt = tx.prepare_cached("SELECT id, data FROM dictionary ORDER BY id DESC LIMIT 1")?;
+    let dictionary = stmt
+        .query_row(params![], |row| {
+            Ok(Dictionary {
+                id: row.get(0)?,
+                data: row.get(1)?,
+            })
+        })
+        .optional()?;
+    Ok(dictionary)
+}
+
+pub fn create(tx: &mut rusqlite::Transaction, data: &[u8]) -> Result<u64> {
+    let now = TimeSpec::from(SystemTime::now());
+    let mut stmt = tx.prepare_cached("INSERT INTO dictionary (data, created_secs) VALUES (?, ?)")?;
+    let id = stmt.insert(params![data, now.secs])?;
+    Ok(id as u64)
+}
+
+/// Returns the dictionary `id`, loading it into `cache` unless it is already there. Data referencing a dictionary that
+/// does not exist is corrupted.
+pub fn get_cached<'c>(
+    tx: &rusqlite::Transaction,
+    cache: &'c mut Option<Dictionary>,
+    id: Option<u64>,
+) -> Result<Option<&'c Dictionary>> {
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    if cache.as_ref().map(|d| d.id) != Some(id) {
+        *cache = Some(get(tx, id).map_err(|e| match e {
+            Error::NotFound => Error::Corrupted,
+            e => e,
+        })?);
+    }
+    Ok(cache.as_ref())
+}
@@ -1,4 +1,8 @@
-use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+};
 
 use crate::{
     errors::{Error, Result},
@@ -12,6 +16,19 @@ pub fn lookup(tx: &mut rusqlite::Transaction, parent_ino: u64, name: &OsStr) ->
     Ok(ino)
 }
 
+/// Resolves an absolute path inside the filesystem to an inode, starting from the root directory.
+pub fn resolve(tx: &mut rusqlite::Transaction, path: &Path) -> Result<u64> {
+    let mut ino = 1;
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => ino = lookup(tx, ino, name)?,
+            Component::ParentDir | Component::Prefix(_) => return Err(Error::InvalidArgument),
+        }
+    }
+    Ok(ino)
+}
+
 pub fn create(tx: &mut rusqlite::Transaction, parent_ino: u64, name: &OsStr, ino: u64) -> Result<()> {
     let mut stmt = tx.prepare_cached(include_str!("sql/create_dir_entry.sql"))?;
     stmt.insert(params![parent_ino, name.as_encoded_bytes(), ino])?;
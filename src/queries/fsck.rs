@@ -0,0 +1,226 @@
+//! Consistency checks of the filesystem tables, used by the `fsck` command. They read the raw columns instead of
+//! going through `inode::lookup` so that invalid rows are reported rather than rejected.
+
+use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+use crate::{errors::Result, types::FileType};
+use rusqlite::params;
+
+pub fn exists(tx: &mut rusqlite::Transaction, ino: u64) -> Result<bool> {
+    let mut stmt = tx.prepare_cached("SELECT EXISTS(SELECT 1 FROM inode WHERE ino = ?)")?;
+    Ok(stmt.query_row(params![ino], |row| row.get(0))?)
+}
+
+/// Changes the number of an inode, its directory entries and blocks follow through `ON UPDATE CASCADE`.
+pub fn renumber(tx: &mut rusqlite::Transaction, ino: u64, new_ino: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("UPDATE inode SET ino = ? WHERE ino = ?")?;
+    stmt.execute(params![new_ino, ino])?;
+    Ok(())
+}
+
+/// Returns the `(ino, kind)` of the inodes whose kind is not a known `FileType`.
+pub fn invalid_kinds(tx: &mut rusqlite::Transaction) -> Result<Vec<(u64, i64)>> {
+    let mut stmt = tx.prepare_cached("SELECT ino, kind FROM inode ORDER BY ino")?;
+    let kinds = stmt
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(u64, i64)>>>()?;
+    Ok(kinds
+        .into_iter()
+        .filter(|&(_, kind)| u8::try_from(kind).map_or(true, |k| FileType::try_from(k).is_err()))
+        .collect())
+}
+
+/// Returns the distinct compression values of the blocks, and of the inline data when `inline` is set.
+pub fn compressions(tx: &mut rusqlite::Transaction, inline: bool) -> Result<Vec<Option<i64>>> {
+    let sql = match inline {
+        false => "SELECT DISTINCT compression FROM block",
+        true => "SELECT DISTINCT inline_compression FROM inode WHERE inline_data IS NOT NULL",
+    };
+    let mut stmt = tx.prepare_cached(sql)?;
+    let values = stmt
+        .query_map(params![], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(values)
+}
+
+/// Returns the `(ino, bno)` of the blocks stored with `compression`.
+pub fn blocks_with_compression(tx: &mut rusqlite::Transaction, compression: i64) -> Result<Vec<(u64, u64)>> {
+    let mut stmt = tx.prepare_cached("SELECT ino, bno FROM block WHERE compression = ? ORDER BY ino, bno")?;
+    let blocks = stmt
+        .query_map(params![compression], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(blocks)
+}
+
+/// Returns the inodes whose inline data is stored with `compression`.
+pub fn inline_with_compression(tx: &mut rusqlite::Transaction, compression: i64) -> Result<Vec<u64>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT ino FROM inode WHERE inline_data IS NOT NULL AND inline_compression = ? ORDER BY ino",
+    )?;
+    let inos = stmt
+        .query_map(params![compression], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    Ok(inos)
+}
+
+pub fn remove_block(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM block WHERE ino = ? AND bno = ?")?;
+    stmt.execute(params![ino, bno])?;
+    Ok(())
+}
+
+/// Returns the inode numbers referenced by blocks that have no inode.
+pub fn orphan_blocks(tx: &mut rusqlite::Transaction) -> Result<Vec<u64>> {
+    let mut stmt =
+        tx.prepare_cached("SELECT DISTINCT ino FROM block WHERE ino NOT IN (SELECT ino FROM inode) ORDER BY ino")?;
+    let inos = stmt
+        .query_map(params![], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    Ok(inos)
+}
+
+pub fn remove_blocks(tx: &mut rusqlite::Transaction, ino: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM block WHERE ino = ?")?;
+    stmt.execute(params![ino])?;
+    Ok(())
+}
+
+pub struct Entry {
+    pub rowid: i64,
+    pub parent_ino: u64,
+    pub name: OsString,
+    pub ino: u64,
+}
+
+fn entries(tx: &mut rusqlite::Transaction, sql: &str, p: impl rusqlite::Params) -> Result<Vec<Entry>> {
+    let mut stmt = tx.prepare_cached(sql)?;
+    let entries = stmt
+        .query_map(p, |row| {
+            Ok(Entry {
+                rowid: row.get(0)?,
+                parent_ino: row.get(1)?,
+                name: OsString::from_vec(row.get(2)?),
+                ino: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(entries)
+}
+
+/// Returns the directory entries whose inode is missing, or whose parent is missing or not a directory.
+pub fn dangling_entries(tx: &mut rusqlite::Transaction) -> Result<Vec<Entry>> {
+    entries(
+        tx,
+        "SELECT d.rowid, d.parent_ino, d.name, d.ino FROM dir_entry d
+        LEFT JOIN inode p ON p.ino = d.parent_ino
+        LEFT JOIN inode c ON c.ino = d.ino
+        WHERE p.ino IS NULL OR c.ino IS NULL OR p.kind != ?
+        ORDER BY d.rowid",
+        params![u8::from(FileType::Directory)],
+    )
+}
+
+/// Returns the directory entries that have the same name as an older entry of the same directory.
+pub fn duplicate_entries(tx: &mut rusqlite::Transaction) -> Result<Vec<Entry>> {
+    entries(
+        tx,
+        "SELECT d.rowid, d.parent_ino, d.name, d.ino FROM dir_entry d
+        WHERE EXISTS (SELECT 1 FROM dir_entry o WHERE o.parent_ino = d.parent_ino AND o.name = d.name AND o.rowid < d.rowid)
+        ORDER BY d.rowid",
+        params![],
+    )
+}
+
+pub fn remove_entry(tx: &mut rusqlite::Transaction, rowid: i64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM dir_entry WHERE rowid = ?")?;
+    stmt.execute(params![rowid])?;
+    Ok(())
+}
+
+pub fn rename_entry(tx: &mut rusqlite::Transaction, rowid: i64, parent_ino: u64, name: &[u8]) -> Result<()> {
+    let mut stmt = tx.prepare_cached("UPDATE dir_entry SET parent_ino = ?, name = ? WHERE rowid = ?")?;
+    stmt.execute(params![parent_ino, name, rowid])?;
+    Ok(())
+}
+
+pub fn entries_of(tx: &mut rusqlite::Transaction, ino: u64) -> Result<Vec<Entry>> {
+    entries(
+        tx,
+        "SELECT rowid, parent_ino, name, ino FROM dir_entry WHERE ino = ? ORDER BY rowid",
+        params![ino],
+    )
+}
+
+pub struct Unreachable {
+    pub ino: u64,
+    pub kind: i64,
+    /// False when no directory entry references the inode at all.
+    pub linked: bool,
+}
+
+/// Returns the inodes that cannot be reached by walking directory entries from the root directory.
+pub fn unreachable(tx: &mut rusqlite::Transaction) -> Result<Vec<Unreachable>> {
+    let mut stmt = tx.prepare_cached(
+        "WITH RECURSIVE reachable(ino) AS (
+            SELECT 1 UNION SELECT d.ino FROM dir_entry d JOIN reachable r ON d.parent_ino = r.ino
+        )
+        SELECT i.ino, i.kind, EXISTS(SELECT 1 FROM dir_entry d WHERE d.ino = i.ino) FROM inode i
+        WHERE i.ino NOT IN reachable
+        ORDER BY i.ino",
+    )?;
+    let inos = stmt
+        .query_map(params![], |row| {
+            Ok(Unreachable {
+                ino: row.get(0)?,
+                kind: row.get(1)?,
+                linked: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(inos)
+}
+
+/// Returns `(ino, kind, nlink, number of directory entries)` for every inode.
+pub fn link_counts(tx: &mut rusqlite::Transaction) -> Result<Vec<(u64, i64, u64, u64)>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT i.ino, i.kind, i.nlink, (SELECT count(*) FROM dir_entry d WHERE d.ino = i.ino) FROM inode i ORDER BY i.ino",
+    )?;
+    let counts = stmt
+        .query_map(params![], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(counts)
+}
+
+pub struct FileSize {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub blksize: u64,
+    /// Highest block number, `None` when the file has no blocks.
+    pub last_bno: Option<u64>,
+    pub inline: bool,
+}
+
+/// Returns the size related attributes of every regular file.
+pub fn file_sizes(tx: &mut rusqlite::Transaction) -> Result<Vec<FileSize>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT i.ino, i.size, i.blocks, i.blksize, (SELECT max(bno) FROM block b WHERE b.ino = i.ino AND b.bno >= 0),
+            i.inline_data IS NOT NULL
+        FROM inode i WHERE i.kind = ? ORDER BY i.ino",
+    )?;
+    let sizes = stmt
+        .query_map(params![u8::from(FileType::RegularFile)], |row| {
+            Ok(FileSize {
+                ino: row.get(0)?,
+                size: row.get(1)?,
+                blocks: row.get(2)?,
+                blksize: row.get(3)?,
+                last_bno: row.get(4)?,
+                inline: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(sizes)
+}
@@ -1,5 +1,9 @@
 use crate::{
     errors::{Error, Result},
+    queries::{
+        block::{Block, CompressedBlock, CompressionSpec, Compressor},
+        dictionary,
+    },
     time::TimeSpec,
     types::FileType,
 };
@@ -30,6 +34,12 @@ pub fn lookup(tx: &mut rusqlite::Transaction, ino: u64) -> Result<fuser::FileAtt
     Ok(attr)
 }
 
+/// Returns the size of the inode without reading its other attributes.
+pub fn get_size(tx: &mut rusqlite::Transaction, ino: u64) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT size FROM inode WHERE ino = ?")?;
+    Ok(stmt.query_row(params![ino], |row| row.get(0))?)
+}
+
 pub fn create(tx: &mut rusqlite::Transaction, attr: &mut fuser::FileAttr) -> Result<()> {
     let atime = TimeSpec::from(attr.atime);
     let mtime = TimeSpec::from(attr.mtime);
@@ -76,6 +86,109 @@ pub fn set_attr(
     }
 }
 
+/// Returns the compression policy of the inode, `None` when it uses the database default.
+pub fn get_compression(tx: &mut rusqlite::Transaction, ino: u64) -> Result<Option<CompressionSpec>> {
+    let mut stmt = tx.prepare_cached("SELECT compression FROM inode WHERE ino = ?")?;
+    let spec: Option<String> = stmt.query_row(params![ino], |row| row.get(0))?;
+    spec.map(|spec| spec.parse().map_err(|_| Error::InvalidCompression))
+        .transpose()
+}
+
+pub fn set_compression(tx: &mut rusqlite::Transaction, ino: u64, compression: Option<CompressionSpec>) -> Result<()> {
+    set_attr(tx, ino, "compression", compression.map(|c| c.to_string()))
+}
+
+/// Copies the compression policy of the `parent` directory to `ino`.
+pub fn inherit_compression(tx: &mut rusqlite::Transaction, parent: u64, ino: u64) -> Result<()> {
+    let mut stmt = tx
+        .prepare_cached("UPDATE inode SET compression = (SELECT compression FROM inode WHERE ino = ?) WHERE ino = ?")?;
+    stmt.execute(params![parent, ino])?;
+    Ok(())
+}
+
+/// Returns the inline data of the inode as block 0, or `None` if the file contents live in the block table.
+pub fn get_inline(tx: &mut rusqlite::Transaction, ino: u64, block_size: u64) -> Result<Option<Block>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT inline_data, inline_compression, inline_dict_id, inline_checksum FROM inode WHERE ino = ?",
+    )?;
+    let mut rows = stmt.query(params![ino])?;
+    match rows.next()? {
+        Some(row) => {
+            let Some(data) = row.get_ref(0)?.as_blob_or_null()? else {
+                return Ok(None);
+            };
+            let compression: Option<u8> = row.get(1)?;
+            let mut cache = None;
+            let block = CompressedBlock {
+                ino,
+                bno: 0,
+                block_size,
+                compression: compression.try_into()?,
+                dictionary: dictionary::get_cached(tx, &mut cache, row.get(2)?)?,
+                checksum: row.get(3)?,
+                data,
+            };
+            block.decompress().map(Some)
+        }
+        None => Err(Error::NotFound),
+    }
+}
+
+/// Stores `block` as the inline data of its inode and returns the size of the stored data.
+pub fn set_inline(tx: &mut rusqlite::Transaction, block: &Block, compressor: &mut Compressor) -> Result<u64> {
+    let mut buf = Vec::new();
+    let cb = CompressedBlock::compress(block, compressor, &mut buf);
+
+    let mut stmt = tx
+        .prepare_cached("UPDATE inode SET inline_data = ?, inline_compression = ?, inline_dict_id = ?, inline_checksum = ? WHERE ino = ?")?;
+    let affected = stmt.execute(params![
+        cb.data,
+        cb.compression as u8,
+        cb.dict_id(),
+        cb.checksum,
+        block.ino
+    ])?;
+    match affected {
+        0 => Err(Error::NotFound),
+        _ => Ok(cb.data.len() as u64),
+    }
+}
+
+/// Returns up to `limit` `(ino, stored size)` of the inodes with inline data following `ino`, in order.
+pub fn list_inline_after(tx: &mut rusqlite::Transaction, ino: u64, limit: usize) -> Result<Vec<(u64, u64)>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT ino, length(inline_data) FROM inode WHERE inline_data IS NOT NULL AND ino > ? ORDER BY ino LIMIT ?",
+    )?;
+    let inos = stmt
+        .query_map(params![ino, limit], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(inos)
+}
+
+pub fn count_inline_after(tx: &mut rusqlite::Transaction, ino: u64) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT count(*) FROM inode WHERE inline_data IS NOT NULL AND ino > ?")?;
+    let count = stmt.query_row(params![ino], |row| row.get(0))?;
+    Ok(count)
+}
+
+/// Returns up to `limit` randomly chosen inodes that have inline data.
+pub fn sample_inline(tx: &mut rusqlite::Transaction, limit: usize) -> Result<Vec<u64>> {
+    let mut stmt =
+        tx.prepare_cached("SELECT ino FROM inode WHERE inline_data IS NOT NULL ORDER BY random() LIMIT ?")?;
+    let inos = stmt
+        .query_map(params![limit], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    Ok(inos)
+}
+
+pub fn clear_inline(tx: &mut rusqlite::Transaction, ino: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "UPDATE inode SET inline_data = NULL, inline_compression = NULL, inline_dict_id = NULL, inline_checksum = NULL WHERE ino = ?",
+    )?;
+    stmt.execute(params![ino])?;
+    Ok(())
+}
+
 pub fn remove(tx: &mut rusqlite::Transaction, ino: u64) -> Result<()> {
     let mut stmt = tx.prepare_cached("DELETE FROM inode WHERE ino = ?")?;
     let affected = stmt.execute(params![ino])?;
@@ -0,0 +1,105 @@
+use crate::errors::{Error, Result};
+use crate::queries::block::{CompressionSpec, DEFAULT_MIN_SAVINGS};
+use rusqlite::{params, types::FromSql, OptionalExtension, ToSql};
+
+pub const BLOCK_SIZE: &str = "block_size";
+pub const COMPRESSION: &str = "compression";
+pub const MIN_SAVINGS: &str = "min_savings";
+pub const RECOMPRESS_TARGET: &str = "recompress.target";
+pub const RECOMPRESS_CURSOR: &str = "recompress.cursor";
+pub const UUID: &str = "uuid";
+pub const LABEL: &str = "label";
+pub const CREATED_BY: &str = "created_by";
+pub const CREATED_AT: &str = "created_at";
+
+/// Format features understood by this version of nightshift, stored as `feature.<name>` keys.
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    "inline_data",
+    "block_size",
+    "zstd_dict",
+    "xz",
+    "brotli",
+    "block_checksum",
+];
+const FEATURE_PREFIX: &str = "feature.";
+
+pub fn get<T: FromSql>(tx: &mut rusqlite::Transaction, key: &str) -> Result<Option<T>> {
+    let mut stmt = tx.prepare_cached("SELECT value FROM meta WHERE key = ?")?;
+    let value = stmt.query_row(params![key], |row| row.get(0)).optional()?;
+    Ok(value)
+}
+
+pub fn set(tx: &mut rusqlite::Transaction, key: &str, value: impl ToSql) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO meta (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+    )?;
+    stmt.execute(params![key, value])?;
+    Ok(())
+}
+
+pub fn delete(tx: &mut rusqlite::Transaction, key: &str) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM meta WHERE key = ?")?;
+    stmt.execute(params![key])?;
+    Ok(())
+}
+
+pub fn block_size(tx: &mut rusqlite::Transaction) -> Result<u64> {
+    get(tx, BLOCK_SIZE)?.ok_or(Error::NotFound)
+}
+
+/// Returns the compression used when none is given on the command line.
+pub fn compression(tx: &mut rusqlite::Transaction) -> Result<CompressionSpec> {
+    match get::<String>(tx, COMPRESSION)? {
+        Some(spec) => spec.parse().map_err(|_| Error::InvalidCompression),
+        None => Ok(CompressionSpec::default()),
+    }
+}
+
+pub fn set_compression(tx: &mut rusqlite::Transaction, compression: CompressionSpec) -> Result<()> {
+    enable_compression(tx, compression)?;
+    set(tx, COMPRESSION, compression.to_string())
+}
+
+/// Returns the percentage a block must shrink by to be stored compressed.
+pub fn min_savings(tx: &mut rusqlite::Transaction) -> Result<u8> {
+    Ok(get(tx, MIN_SAVINGS)?.unwrap_or(DEFAULT_MIN_SAVINGS))
+}
+
+/// Returns every key and value, values are converted to text.
+pub fn list(tx: &mut rusqlite::Transaction) -> Result<Vec<(String, String)>> {
+    let mut stmt = tx.prepare_cached("SELECT key, CAST(value AS TEXT) FROM meta ORDER BY key")?;
+    let entries = stmt
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(entries)
+}
+
+/// Marks the database as using `feature`, older versions of nightshift will refuse to open it.
+pub fn enable_feature(tx: &mut rusqlite::Transaction, feature: &str) -> Result<()> {
+    set(tx, &format!("{FEATURE_PREFIX}{feature}"), 1)
+}
+
+/// Enables the feature required to write blocks with `compression`, if any.
+pub fn enable_compression(tx: &mut rusqlite::Transaction, compression: CompressionSpec) -> Result<()> {
+    match compression.compression.feature() {
+        Some(feature) => enable_feature(tx, feature),
+        None => Ok(()),
+    }
+}
+
+pub fn features(tx: &mut rusqlite::Transaction) -> Result<Vec<String>> {
+    let mut stmt = tx.prepare_cached("SELECT substr(key, ?) FROM meta WHERE key LIKE ? ORDER BY key")?;
+    let features = stmt
+        .query_map(params![FEATURE_PREFIX.len() + 1, format!("{FEATURE_PREFIX}%")], |row| {
+            row.get(0)
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(features)
+}
+
+/// Returns the features used by the database that this version of nightshift does not understand.
+pub fn unsupported_features(tx: &mut rusqlite::Transaction) -> Result<Vec<String>> {
+    let mut features = features(tx)?;
+    features.retain(|f| !SUPPORTED_FEATURES.contains(&f.as_str()));
+    Ok(features)
+}
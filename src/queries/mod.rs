@@ -1,3 +1,6 @@
 pub mod block;
+pub mod dictionary;
 pub mod dir_entry;
+pub mod fsck;
 pub mod inode;
+pub mod meta;
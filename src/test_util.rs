@@ -0,0 +1,92 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use rand::RngCore;
+
+use crate::commands::put::put;
+use crate::database::{CreateOptions, DatabaseOps};
+use crate::driver::attr::FileAttrBuilder;
+use crate::driver::{FileHandle, OpenFlags};
+use crate::queries;
+use crate::queries::block::{Compression, Compressor, DEFAULT_BLOCK_SIZE};
+use crate::types::FileType;
+
+/// 300 KiB of compressible data, spanning three blocks of the default size.
+pub fn sample_data() -> Vec<u8> {
+    (0..300 * 1024).map(|i| (i % 251) as u8).collect()
+}
+
+/// Log lines, picked as zstd by `Compression::Auto`.
+pub fn text_data() -> Vec<u8> {
+    b"2024-08-01 12:00:00 INFO request handled in 12ms\n".repeat(4000)
+}
+
+/// Mostly random data picked as LZ4 by `Compression::Auto`, with a compressible tail in every 4 KiB so that it is not
+/// stored uncompressed.
+pub fn media_data(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    for chunk in data.chunks_mut(4096) {
+        let random = chunk.len() * 3 / 4;
+        rand::thread_rng().fill_bytes(&mut chunk[..random]);
+    }
+    data
+}
+
+/// Creates an inode of `kind` holding `data` compressed with LZ4, named `name` in `parent` unless `parent` is `None`.
+pub fn create(
+    db: &mut DatabaseOps,
+    parent: Option<u64>,
+    name: &str,
+    kind: FileType,
+    data: &[u8],
+) -> anyhow::Result<u64> {
+    create_with(db, parent, name, kind, data, Compressor::new(Compression::LZ4.into()))
+}
+
+/// Like `create`, with `data` compressed by `compressor`.
+pub fn create_with(
+    db: &mut DatabaseOps,
+    parent: Option<u64>,
+    name: &str,
+    kind: FileType,
+    data: &[u8],
+    compressor: Compressor,
+) -> anyhow::Result<u64> {
+    let ino = db.with_write_tx(|tx| {
+        let mut attr = match kind {
+            FileType::Directory => FileAttrBuilder::new_directory().build(),
+            kind => FileAttrBuilder::new_node(kind).build(),
+        };
+        queries::inode::create(tx, &mut attr)?;
+        if let Some(parent) = parent {
+            queries::dir_entry::create(tx, parent, OsStr::new(name), attr.ino)?;
+        }
+        if !data.is_empty() {
+            let mut fh = FileHandle::new(attr.ino, 0, OpenFlags::from(0), DEFAULT_BLOCK_SIZE, compressor);
+            fh.consume_input(data);
+            fh.flush(tx)?;
+        }
+        Ok(attr.ino)
+    })?;
+    Ok(ino)
+}
+
+/// Creates the database `path` encrypted with `key`, holding 300 KiB of random data as `/src/random`. Returns the
+/// database and the data.
+pub fn populated_db(path: &Path, key: Option<String>) -> anyhow::Result<(DatabaseOps, Vec<u8>)> {
+    populated_db_with(path, key, &CreateOptions::default())
+}
+
+/// Like `populated_db`, with the database created with `options`.
+pub fn populated_db_with(
+    path: &Path,
+    key: Option<String>,
+    options: &CreateOptions,
+) -> anyhow::Result<(DatabaseOps, Vec<u8>)> {
+    let tmp = tempfile::tempdir()?;
+    let data: Vec<u8> = (0..300 * 1024).map(|_| rand::random()).collect();
+    std::fs::write(tmp.path().join("random"), &data)?;
+    let mut db = DatabaseOps::create(path, key, options)?;
+    put(&mut db, tmp.path(), Path::new("/src"), |_| {})?;
+    Ok((db, data))
+}
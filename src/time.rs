@@ -1,8 +1,9 @@
 use std::time::{self, Duration, SystemTime};
 
 use fuser::TimeOrNow;
+use serde::Serialize;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
 pub struct TimeSpec {
     pub secs: u64,
     pub nanos: u32,
@@ -14,6 +15,33 @@ impl TimeSpec {
     }
 }
 
+/// Formats the time as `YYYY-MM-DD HH:MM:SS` in UTC.
+impl std::fmt::Display for TimeSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = (self.secs / 86400) as i64 + 719468;
+        let era = days / 146097;
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        let secs = self.secs % 86400;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
 impl From<SystemTime> for TimeSpec {
     fn from(value: SystemTime) -> Self {
         let d = value